@@ -0,0 +1,65 @@
+use crate::output;
+use app_lib::p2p::client::PeerError;
+use app_lib::p2p::devices::Devices;
+use app_lib::p2p::discovery;
+use app_lib::p2p::protocol::SERVICE_TYPE;
+use mdns_sd::{ServiceDaemon, ServiceEvent};
+use serde_json::json;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Prints the devices announcing themselves on the local network, for `timeout` seconds.
+pub fn discover(devices: &Devices, timeout: u64) -> Result<(), PeerError> {
+    let daemon = ServiceDaemon::new().map_err(|e| e.to_string())?;
+    let receiver = daemon.browse(SERVICE_TYPE).map_err(|e| e.to_string())?;
+    let (own_id, _) = devices.identity();
+
+    let deadline = Instant::now() + Duration::from_secs(timeout);
+    let mut seen = HashSet::new();
+    while let Ok(event) = receiver.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+    {
+        let ServiceEvent::ServiceResolved(service) = event else {
+            continue;
+        };
+        let (Some(device_id), Some(device_name), Some(version)) = (
+            service.get_property_val_str("id"),
+            service.get_property_val_str("name"),
+            service.get_property_val_str("version"),
+        ) else {
+            continue;
+        };
+        if device_id == own_id || !seen.insert(device_id.to_string()) {
+            continue;
+        }
+
+        let mut addresses: Vec<_> = service.get_addresses().iter().collect();
+        addresses.sort();
+        output(
+            "discovered",
+            json!({
+                "device_id": device_id,
+                "device_name": device_name,
+                "addresses": addresses,
+                "port": service.get_port(),
+                "version": version.parse::<u32>().ok(),
+                "paired": devices.secret(device_id).is_some()
+            }),
+        );
+    }
+
+    let _ = daemon.shutdown();
+    Ok(())
+}
+
+/// Announces this device on the local network, returning the daemon to keep running while listening.
+pub fn announce(devices: &Devices, address: SocketAddr) -> Option<ServiceDaemon> {
+    let daemon = ServiceDaemon::new().ok()?;
+    match discovery::register(&daemon, devices, address) {
+        Ok(()) => Some(daemon),
+        Err(e) => {
+            output("announce_failed", json!({ "error": e.to_string() }));
+            None
+        }
+    }
+}
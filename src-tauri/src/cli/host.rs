@@ -0,0 +1,162 @@
+use crate::output;
+use app_lib::p2p::config::P2pConfig;
+use app_lib::p2p::devices::{random_hex, Devices};
+use app_lib::p2p::history::Outcome;
+use app_lib::p2p::host::{Host, Transfer};
+use app_lib::p2p::transfers::{Direction, PartialPolicy, TransferCommand};
+use serde_json::json;
+use std::io::{BufRead, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// How pairings (asked for by a peer, or started here) are confirmed.
+pub enum Pairing {
+    /// Peers asking to pair are declined (pairings started here are asked about).
+    Decline,
+    /// The user answers on the terminal, after comparing the code.
+    Ask,
+}
+
+/// Runs the protocol without a window: events go to stdout, files are accepted without asking.
+pub struct Headless {
+    pub devices: Devices,
+    /// Where received files are saved.
+    pub dir: Option<PathBuf>,
+    /// The port we listen on, if listening.
+    pub port: Option<u16>,
+    pub pairing: Pairing,
+}
+
+/// A transfer, which can't be paused or cancelled from here.
+pub struct Job {
+    id: String,
+    _commands: mpsc::UnboundedSender<TransferCommand>,
+}
+
+impl Transfer for Job {
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn finish(&mut self, _outcome: Outcome, _sha256: Option<String>, _error: Option<String>) {}
+}
+
+impl Host for Headless {
+    type Prompt = ();
+    type Transfer = Job;
+
+    fn devices(&self) -> &Devices {
+        &self.devices
+    }
+
+    fn p2p_config(&self) -> P2pConfig {
+        // Cancelled transfers are kept to resume, there is no one to ask about it.
+        P2pConfig {
+            partial_policy: PartialPolicy::Keep,
+            ..P2pConfig::default()
+        }
+    }
+
+    fn listen_port(&self) -> Option<u16> {
+        self.port
+    }
+
+    fn receive_dir(&self) -> std::io::Result<PathBuf> {
+        self.dir.clone().ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::NotFound, "Not receiving files.")
+        })
+    }
+
+    fn report(&self, event: &str, data: serde_json::Value) {
+        // Progress is left out, a line per chunk would bury everything else.
+        if !event.ends_with("_progress") {
+            output(event, data);
+        }
+    }
+
+    fn ask(&self, event: &str, data: serde_json::Value, answers: mpsc::UnboundedSender<bool>) {
+        output(event, data.clone());
+        if !event.starts_with("pair_") {
+            let _ = answers.send(true);
+            return;
+        }
+
+        match &self.pairing {
+            Pairing::Decline if event == "pair_request" => {
+                let _ = answers.send(false);
+            }
+            _ => {
+                let name = data["device_name"].as_str().unwrap_or_default().to_string();
+                let code = data["code"].as_str().unwrap_or_default().to_string();
+                std::thread::spawn(move || {
+                    let _ = answers.send(confirm(&name, &code));
+                });
+            }
+        }
+    }
+
+    fn log(&self, message: String) {
+        eprintln!("{}", message);
+    }
+
+    fn start_transfer(
+        &self,
+        _direction: Direction,
+        _name: &str,
+        _size: u64,
+        _peer: &str,
+    ) -> (Job, mpsc::UnboundedReceiver<TransferCommand>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let job = Job {
+            id: random_hex(8),
+            _commands: sender,
+        };
+        (job, receiver)
+    }
+
+    fn report_paused(&self, id: &str, paused: bool, by_peer: bool) {
+        let event = if paused {
+            "transfer_paused"
+        } else {
+            "transfer_resumed"
+        };
+        output(event, json!({"transfer_id": id, "by_peer": by_peer}));
+    }
+
+    fn record_compression_ratio(&self, _id: &str, _ratio: f64) {}
+
+    fn throttle(&self, _transfer_id: &str, _bytes: u64) -> Duration {
+        Duration::ZERO
+    }
+
+    fn text_received(&self, peer: SocketAddr, text: String, clipboard: bool) {
+        output(
+            "text_received",
+            json!({"text": text, "clipboard": clipboard, "peer": peer}),
+        );
+    }
+
+    fn redeem_token(&self, _token: &str) -> bool {
+        // There is no connection card to scan here.
+        false
+    }
+
+    fn identify(&self, _peer: SocketAddr, _device_id: String, _device_name: String) {}
+}
+
+/// Asks on the terminal whether the code matches the one the other device shows.
+fn confirm(device_name: &str, code: &str) -> bool {
+    eprint!(
+        "Pair with {} (code {})? Accept only if it shows the same code [y/N] ",
+        device_name, code
+    );
+    let _ = std::io::stderr().flush();
+
+    let mut answer = String::new();
+    match std::io::stdin().lock().read_line(&mut answer) {
+        Ok(_) => matches!(answer.trim(), "y" | "Y" | "yes"),
+        Err(_) => false,
+    }
+}
@@ -0,0 +1,205 @@
+//! A headless P2P peer, to send and receive from scripts.
+//!
+//! It runs the protocol code of the app through a host of its own, without a window. Everything
+//! it reports goes to stdout as one JSON object per line, and the exit code tells how it went.
+
+mod discover;
+mod host;
+
+use app_lib::p2p::client::{self, PeerError};
+use app_lib::p2p::devices::Devices;
+use app_lib::p2p::incoming::{self, listen_on, ACCEPT_RETRY_DELAY};
+use app_lib::p2p::protocol;
+use clap::{Parser, Subcommand};
+use host::{Headless, Pairing};
+use serde_json::json;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+/// Exit codes besides success (0) and invalid arguments (2).
+const EXIT_FAILED: u8 = 1;
+const EXIT_DECLINED: u8 = 3;
+const EXIT_UNPAIRED: u8 = 4;
+
+#[derive(Parser)]
+#[command(
+    version,
+    about = "Send and receive files with Isaac Utils devices, without the app."
+)]
+struct Cli {
+    /// Where this device's identity and paired devices are kept.
+    #[arg(long, global = true)]
+    config_dir: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Pair with a device, once the code shown on both sides is accepted on both.
+    Pair { host: String },
+    /// Send a file or folder to a paired device.
+    Send { host: String, file: PathBuf },
+    /// Send text to a paired device (read from stdin when not given).
+    SendText { host: String, text: Option<String> },
+    /// Receive files and texts from paired devices until stopped.
+    Listen {
+        /// Where received files are saved.
+        #[arg(long)]
+        dir: PathBuf,
+        /// The address to listen on, all interfaces (IPv6 and IPv4) by default.
+        #[arg(long, default_value = "::")]
+        address: IpAddr,
+        #[arg(long, default_value_t = protocol::P2P_PORT)]
+        port: u16,
+        /// Ask whether to accept pair requests (after comparing the code) instead of declining
+        /// them.
+        #[arg(long)]
+        allow_pairing: bool,
+    },
+    /// List the devices announcing themselves on the local network.
+    Discover {
+        /// How long to look for, in seconds.
+        #[arg(long, default_value_t = 3)]
+        timeout: u64,
+    },
+}
+
+/// Prints an event (or result) as a line of JSON.
+pub fn output(event: &str, mut data: serde_json::Value) {
+    data["event"] = json!(event);
+    println!("{}", data);
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let devices = Devices::load(&cli.config_dir.unwrap_or_else(default_config_dir));
+
+    let result = match cli.command {
+        Command::Pair { host } => client::pair(&headless(devices, None, Pairing::Ask), &host, None)
+            .await
+            .map(|device| output("paired", device)),
+        Command::Send { host, file } => {
+            let headless = headless(devices, None, Pairing::Decline);
+            if file.is_dir() {
+                client::send_folder(&headless, &host, &file).await
+            } else {
+                client::send_file(&headless, &host, &file).await
+            }
+        }
+        Command::SendText { host, text } => match text.map_or_else(read_stdin, Ok) {
+            Ok(text) => {
+                let length = text.len();
+                let headless = headless(devices, None, Pairing::Decline);
+                client::send_text(&headless, &host, text, false)
+                    .await
+                    .map(|()| output("sent_text", json!({"length": length, "peer": host})))
+            }
+            Err(e) => Err(PeerError::Failed(e)),
+        },
+        Command::Listen {
+            dir,
+            address,
+            port,
+            allow_pairing,
+        } => {
+            let pairing = if allow_pairing {
+                Pairing::Ask
+            } else {
+                Pairing::Decline
+            };
+            listen(headless(devices, Some(dir), pairing), address, port).await
+        }
+        Command::Discover { timeout } => discover::discover(&devices, timeout),
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(failure) => {
+            let code = match failure {
+                PeerError::Failed(_) | PeerError::TimedOut(_) => EXIT_FAILED,
+                PeerError::Declined(_) => EXIT_DECLINED,
+                PeerError::Unpaired(_) => EXIT_UNPAIRED,
+            };
+            eprintln!(
+                "{}",
+                json!({ "event": "error", "error": failure.to_string() })
+            );
+            ExitCode::from(code)
+        }
+    }
+}
+
+fn headless(devices: Devices, dir: Option<PathBuf>, pairing: Pairing) -> Headless {
+    Headless {
+        devices,
+        dir,
+        port: None,
+        pairing,
+    }
+}
+
+/// Receives files, folders and texts from paired devices (saved to the host's dir), until
+/// interrupted.
+async fn listen(mut host: Headless, address: IpAddr, port: u16) -> Result<(), PeerError> {
+    if let Some(dir) = &host.dir {
+        tokio::fs::create_dir_all(dir)
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+    let listener = listen_on(SocketAddr::new(address, port))
+        .map_err(|e| format!("Can't listen on {}: {}", SocketAddr::new(address, port), e))?;
+    let address = listener.local_addr().map_err(|e| e.to_string())?;
+    host.port = Some(address.port());
+    let _daemon = discover::announce(&host.devices, address);
+    output("listening", json!({"address": address, "dir": host.dir}));
+
+    let host = Arc::new(host);
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = tokio::signal::ctrl_c() => return Ok(()),
+        };
+        let (stream, peer) = match accepted {
+            Ok(accepted) => accepted,
+            // Failing to accept one connection (like when out of file descriptors) is no reason to
+            // stop listening, just wait a bit before trying again.
+            Err(e) => {
+                output("accept_failed", json!({"error": e.to_string()}));
+                tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                continue;
+            }
+        };
+        // IPv4 peers of the dual-stack listener come as IPv4-mapped IPv6 addresses.
+        let peer = SocketAddr::new(peer.ip().to_canonical(), peer.port());
+
+        // Each peer gets its own task so one connection never holds up the others. Nothing sends
+        // it commands, there is no UI to disconnect it from.
+        let host = host.clone();
+        tokio::spawn(async move {
+            let (_commands, receiver) = mpsc::unbounded_channel();
+            if let Err(e) = incoming::handle_connection(peer, stream, &*host, receiver).await {
+                output("peer_error", json!({"error": e.to_string(), "peer": peer}));
+            }
+        });
+    }
+}
+
+/// The per-user config dir, falling back to the current dir.
+fn default_config_dir() -> PathBuf {
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .unwrap_or_default();
+
+    base.join("isaac-utils-cli")
+}
+
+fn read_stdin() -> Result<String, String> {
+    std::io::read_to_string(std::io::stdin()).map_err(|e| e.to_string())
+}
@@ -0,0 +1,622 @@
+use crate::{get_selection, p2p};
+use arboard::Clipboard;
+use futures::future::join_all;
+use ipnet::{Ipv4Net, Ipv6Net};
+use serde_json::json;
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr, SocketAddrV6};
+use std::path::PathBuf;
+use tauri::{
+    include_image,
+    menu::{Menu, MenuBuilder, MenuItemBuilder, PredefinedMenuItem, SubmenuBuilder},
+    tray::{MouseButton, MouseButtonState, TrayIconBuilder, TrayIconEvent},
+    AppHandle, Emitter, Manager, State, Wry,
+};
+use tauri_plugin_global_shortcut::Shortcut;
+use tokio::net::TcpStream;
+
+async fn scan_port(target: SocketAddr, timeout: u64) -> (SocketAddr, bool) {
+    let timeout = tokio::time::Duration::from_secs(timeout);
+
+    match tokio::time::timeout(timeout, TcpStream::connect(&target)).await {
+        Ok(Ok(_)) => (target, true),
+        _ => (target, false),
+    }
+}
+
+/// Probes a host if the port is open, returning the peer's device if it is one.
+async fn probe_host(app: &AppHandle, host: SocketAddr) -> Option<serde_json::Value> {
+    let (_, open) = scan_port(host, 1).await;
+    if !open {
+        return None;
+    }
+
+    p2p::client::probe(app, &host.to_string()).await.ok()
+}
+
+#[tauri::command]
+fn c_unix_to_readable(config: serde_json::Map<String, serde_json::Value>, app: AppHandle) {
+    // Get the selected content.
+    let content = get_selection::get_text();
+
+    // Handle the content from the clipboard.
+    let parsed = content.trim().parse::<f64>();
+    match parsed {
+        Ok(f) => {
+            // Show the unix popup window.
+            if let Some(window) = app.get_webview_window("unix_popup") {
+                let _ = window.emit(
+                    "e_unix_popup",
+                    json!({"number": f as i64, "config": config}),
+                );
+            }
+        }
+        Err(_) => {}
+    };
+}
+
+#[tauri::command]
+fn c_copy(value: String) -> bool {
+    copy_text(value)
+}
+
+/// Copies text to the clipboard, returning whether it worked.
+pub(crate) fn copy_text(value: String) -> bool {
+    let clipboard = Clipboard::new();
+    match clipboard {
+        Ok(mut c) => c.set_text(value).is_ok(),
+        Err(_) => false,
+    }
+}
+
+#[tauri::command]
+fn c_valid_shortcut(shortcut: String) -> bool {
+    let result = Shortcut::try_from(shortcut.as_str());
+    result.is_ok()
+}
+
+/// The longest IPv6 prefix worth sweeping (a /120 has 256 hosts).
+const MIN_IPV6_SWEEP_PREFIX: u8 = 120;
+
+/// Looks for peers on the subnet: hosts with the P2P port open that answer a probe.
+///
+/// The IPv4 subnet (and small IPv6 ones) are swept. Other IPv6 peers, like link-local ones, are
+/// found by the multicast discovery, and probed at the addresses they announce.
+#[tauri::command]
+async fn c_check_ports(app: AppHandle) -> serde_json::Value {
+    let interface = match netdev::get_default_interface() {
+        Ok(interface) => interface,
+        Err(e) => {
+            println!("Error: {}", e);
+            return json!({"results": null});
+        }
+    };
+    println!("Default Interface:");
+    println!("\tIPv4: {:?}", interface.ipv4);
+    println!("\tIPv6: {:?}", interface.ipv6);
+
+    let port = app.state::<p2p::config::Config>().get().listen_port;
+    let mut hosts = Vec::new();
+    if let Some(net) = interface.ipv4.first() {
+        match Ipv4Net::new(net.addr, net.prefix_len) {
+            Ok(nw) => hosts.extend(nw.hosts().map(|host| SocketAddr::new(host.into(), port))),
+            Err(e) => println!("NW Error: {}", e),
+        }
+    }
+    for net in interface
+        .ipv6
+        .iter()
+        .filter(|net| net.prefix_len >= MIN_IPV6_SWEEP_PREFIX && !net.addr.is_unicast_link_local())
+    {
+        if let Ok(nw) = Ipv6Net::new(net.addr, net.prefix_len) {
+            hosts.extend(nw.hosts().map(|host| SocketAddr::new(host.into(), port)));
+        }
+    }
+
+    // Link-local addresses are only reachable through the interface they are on.
+    let devices = app.state::<p2p::devices::Devices>();
+    for peer in app.state::<p2p::discovery::Discovery>().list(&devices) {
+        hosts.extend(peer.addresses.iter().map(|ip| match ip {
+            IpAddr::V6(v6) if v6.is_unicast_link_local() => {
+                SocketAddr::V6(SocketAddrV6::new(*v6, peer.port, 0, interface.index))
+            }
+            _ => SocketAddr::new(*ip, peer.port),
+        }));
+    }
+    if hosts.is_empty() {
+        return json!({"results": null});
+    }
+    hosts.sort();
+    hosts.dedup();
+
+    // Peers reachable at several addresses are listed once, at their IPv4 one if they have it.
+    let (own_id, _) = devices.identity();
+    let mut seen = HashSet::new();
+    let results = join_all(hosts.into_iter().map(|host| probe_host(&app, host))).await;
+    let peers: Vec<_> = results
+        .into_iter()
+        .flatten()
+        .filter(|peer| peer["device_id"] != own_id.as_str())
+        .filter(|peer| seen.insert(peer["device_id"].to_string()))
+        .collect();
+    json!({"results": peers})
+}
+
+#[tauri::command]
+fn c_p2p_discovered(
+    discovery: State<'_, p2p::discovery::Discovery>,
+    devices: State<'_, p2p::devices::Devices>,
+) -> Vec<p2p::discovery::DiscoveredPeer> {
+    discovery.list(&devices)
+}
+
+#[tauri::command]
+fn c_p2p_peers(peers: State<'_, p2p::peers::Peers>) -> Vec<p2p::peers::PeerInfo> {
+    peers.list()
+}
+
+#[tauri::command]
+fn c_p2p_disconnect(peer: SocketAddr, peers: State<'_, p2p::peers::Peers>) -> bool {
+    peers.send(&peer, p2p::peers::PeerCommand::Disconnect)
+}
+
+#[tauri::command]
+async fn c_p2p_send_file(address: String, path: String, app: AppHandle) -> Result<(), String> {
+    p2p::client::send_file(&app, &address, &PathBuf::from(path))
+        .await
+        .map_err(String::from)
+}
+
+#[tauri::command]
+async fn c_p2p_send_folder(address: String, path: String, app: AppHandle) -> Result<(), String> {
+    p2p::client::send_folder(&app, &address, &PathBuf::from(path))
+        .await
+        .map_err(String::from)
+}
+
+#[tauri::command]
+async fn c_p2p_send_text(address: String, text: String, app: AppHandle) -> Result<(), String> {
+    p2p::client::send_text(&app, &address, text, false)
+        .await
+        .map_err(String::from)
+}
+
+/// Sends text (the selected text by default) to a device (the default peer by default).
+///
+/// Without a device to send to, the main window is shown to pick one, and the text is returned
+/// for it.
+#[tauri::command]
+async fn c_p2p_send_selection(
+    device_id: Option<String>,
+    text: Option<String>,
+    app: AppHandle,
+) -> Result<serde_json::Value, String> {
+    let text = match text {
+        Some(text) => text,
+        None => tokio::task::spawn_blocking(get_selection::get_text)
+            .await
+            .map_err(|e| e.to_string())?,
+    };
+    if text.trim().is_empty() {
+        return Err("Nothing is selected.".to_string());
+    }
+
+    let picked = device_id.is_some();
+    let device_id = device_id.or(app.state::<p2p::config::Config>().get().default_peer);
+    match device_id.as_deref().and_then(|id| device_address(&app, id)) {
+        Some(address) => {
+            p2p::client::send_text(&app, &address, text, false).await?;
+            Ok(json!({ "address": address }))
+        }
+        None if picked => Err("The device can not be reached.".to_string()),
+        None => {
+            show_main_window(&app);
+            Ok(json!({ "text": text }))
+        }
+    }
+}
+
+/// Returns where a device can be reached: where it is announced, or else where it was last seen.
+fn device_address(app: &AppHandle, device_id: &str) -> Option<String> {
+    if let Some(address) = app.state::<p2p::discovery::Discovery>().address(device_id) {
+        return Some(address.to_string());
+    }
+
+    app.state::<p2p::devices::Devices>()
+        .trusted()
+        .into_iter()
+        .find(|d| d.device_id == device_id)
+        .and_then(|d| d.address)
+}
+
+#[tauri::command]
+fn c_p2p_clipboard_sync(
+    enabled: Option<bool>,
+    sync: State<'_, p2p::clipboard::ClipboardSync>,
+    app: AppHandle,
+) -> bool {
+    if let Some(enabled) = enabled {
+        sync.set_enabled(&app, enabled);
+    }
+
+    sync.enabled()
+}
+
+#[tauri::command]
+fn c_p2p_device_clipboard_sync(
+    device_id: String,
+    enabled: bool,
+    devices: State<'_, p2p::devices::Devices>,
+    sync: State<'_, p2p::clipboard::ClipboardSync>,
+) -> bool {
+    if !enabled {
+        sync.stop_device(&device_id);
+    }
+
+    devices.set_clipboard_sync(&device_id, enabled)
+}
+
+#[tauri::command]
+async fn c_p2p_pair(address: String, app: AppHandle) -> Result<serde_json::Value, String> {
+    p2p::client::pair(&app, &address, None)
+        .await
+        .map_err(String::from)
+}
+
+#[tauri::command]
+async fn c_p2p_pair_link(link: String, app: AppHandle) -> Result<serde_json::Value, String> {
+    let (address, token) =
+        p2p::card::parse_link(&link).ok_or("Invalid connection card link.".to_string())?;
+    p2p::client::pair(&app, &address, Some(token))
+        .await
+        .map_err(String::from)
+}
+
+#[tauri::command]
+fn c_p2p_connection_card(app: AppHandle) -> Result<p2p::card::ConnectionCard, String> {
+    p2p::card::card(&app)
+}
+
+#[tauri::command]
+fn c_p2p_copy_connection_card(app: AppHandle) -> Result<String, String> {
+    p2p::card::copy(&app)
+}
+
+#[tauri::command]
+fn c_p2p_devices(devices: State<'_, p2p::devices::Devices>) -> serde_json::Value {
+    let (device_id, device_name) = devices.identity();
+    let trusted: Vec<_> = devices
+        .trusted()
+        .into_iter()
+        .map(|d| {
+            json!({
+                "device_id": d.device_id,
+                "device_name": d.device_name,
+                "public_key": d.public_key,
+                "paired_at": d.paired_at,
+                "clipboard_sync": d.clipboard_sync
+            })
+        })
+        .collect();
+
+    json!({
+        "device_id": device_id,
+        "device_name": device_name,
+        "public_key": devices.public_key(),
+        "trusted": trusted
+    })
+}
+
+#[tauri::command]
+fn c_p2p_forget_device(device_id: String, devices: State<'_, p2p::devices::Devices>) -> bool {
+    devices.forget(&device_id)
+}
+
+#[tauri::command]
+fn c_p2p_transfers(
+    transfers: State<'_, p2p::transfers::Transfers>,
+) -> Vec<p2p::transfers::TransferInfo> {
+    transfers.list()
+}
+
+#[tauri::command]
+fn c_p2p_pause_transfer(id: String, transfers: State<'_, p2p::transfers::Transfers>) -> bool {
+    transfers.send(&id, p2p::transfers::TransferCommand::Pause)
+}
+
+#[tauri::command]
+fn c_p2p_resume_transfer(id: String, transfers: State<'_, p2p::transfers::Transfers>) -> bool {
+    transfers.send(&id, p2p::transfers::TransferCommand::Resume)
+}
+
+#[tauri::command]
+fn c_p2p_cancel_transfer(id: String, transfers: State<'_, p2p::transfers::Transfers>) -> bool {
+    transfers.send(&id, p2p::transfers::TransferCommand::Cancel)
+}
+
+#[tauri::command]
+fn c_p2p_set_transfer_rate_limit(
+    id: String,
+    rate_limit: Option<u64>,
+    transfers: State<'_, p2p::transfers::Transfers>,
+) -> bool {
+    transfers.set_rate_limit(&id, rate_limit)
+}
+
+#[tauri::command]
+fn c_p2p_history(
+    query: Option<p2p::history::HistoryQuery>,
+    history: State<'_, p2p::history::History>,
+) -> Vec<p2p::history::HistoryEntry> {
+    history.query(&query.unwrap_or_default())
+}
+
+#[tauri::command]
+fn c_p2p_clear_history(history: State<'_, p2p::history::History>) -> Result<(), String> {
+    history.clear().map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn c_p2p_export_history(
+    path: String,
+    history: State<'_, p2p::history::History>,
+) -> Result<(), String> {
+    history
+        .export(&PathBuf::from(path))
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn c_p2p_listen_status(state: State<'_, p2p::server::ListenState>) -> p2p::server::ListenStatus {
+    state.get()
+}
+
+#[tauri::command]
+fn c_p2p_share_file(
+    path: String,
+    web: State<'_, p2p::web::Web>,
+) -> Result<p2p::web::SharedFile, String> {
+    web.share(&PathBuf::from(path))
+}
+
+#[tauri::command]
+fn c_p2p_unshare_file(id: String, web: State<'_, p2p::web::Web>) -> bool {
+    web.unshare(&id)
+}
+
+#[tauri::command]
+fn c_p2p_share_link(id: String, app: AppHandle) -> Result<String, String> {
+    p2p::web::share_link(&app, &id)
+}
+
+#[tauri::command]
+fn c_p2p_shared_files(web: State<'_, p2p::web::Web>) -> Vec<p2p::web::SharedFile> {
+    web.shared()
+}
+
+#[tauri::command]
+fn c_p2p_reload_config(config: State<'_, p2p::config::Config>) -> serde_json::Value {
+    let (config, errors) = config.reload();
+    json!({ "config": config, "errors": errors })
+}
+
+fn tray_menu(app: &AppHandle) -> Result<Menu<Wry>, tauri::Error> {
+    let show_hide = MenuItemBuilder::with_id("show_hide", "Show/Hide").build(app)?;
+    let divider = PredefinedMenuItem::separator(app)?;
+    let check_for_update =
+        MenuItemBuilder::with_id("check_for_update", "Check for Update").build(app)?;
+    let quit = MenuItemBuilder::with_id("quit", "Quit").build(app)?;
+
+    // Active transfers are listed so they can be cancelled from the tray.
+    let transfers = app.state::<p2p::transfers::Transfers>().list();
+    let transfer_items = transfers
+        .iter()
+        .map(|t| {
+            let action = match t.direction {
+                p2p::transfers::Direction::Send => "sending",
+                p2p::transfers::Direction::Receive => "receiving",
+            };
+            let paused = if t.paused { " (paused)" } else { "" };
+            MenuItemBuilder::with_id(
+                format!("cancel_transfer:{}", t.id),
+                format!("Cancel {} {}{}", action, t.name, paused),
+            )
+            .build(app)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut transfers_menu = SubmenuBuilder::new(app, "Transfers").enabled(!transfers.is_empty());
+    for item in &transfer_items {
+        transfers_menu = transfers_menu.item(item);
+    }
+    let transfers_menu = transfers_menu.build()?;
+
+    // The last text a peer sent, as desktop notifications have no buttons to copy it (or open its
+    // link) with.
+    let received = app.state::<p2p::texts::ReceivedText>().get();
+    let copy_received = MenuItemBuilder::with_id("copy_received_text", "Copy Received Text")
+        .enabled(received.is_some())
+        .build(app)?;
+    let open_received = MenuItemBuilder::with_id("open_received_link", "Open Received Link")
+        .enabled(received.is_some_and(|(_, url)| url.is_some()))
+        .build(app)?;
+
+    MenuBuilder::new(app)
+        .items(&[
+            &show_hide,
+            &transfers_menu,
+            &copy_received,
+            &open_received,
+            &divider,
+            &check_for_update,
+            &quit,
+        ])
+        .build()
+}
+
+/// Rebuilds the tray menu, e.g. when transfers start or end.
+pub(crate) fn update_tray(app: &AppHandle) {
+    if let Some(tray) = app.tray_by_id("main") {
+        if let Ok(menu) = tray_menu(app) {
+            let _ = tray.set_menu(Some(menu));
+        }
+    }
+}
+
+fn make_tray(app: &tauri::App) -> Result<(), tauri::Error> {
+    let menu = tray_menu(app.handle())?;
+    let _tray = TrayIconBuilder::with_id("main")
+        .menu(&menu)
+        .on_menu_event(|app, event| match event.id().as_ref() {
+            "show_hide" => {
+                if let Some(window) = app.get_webview_window("main") {
+                    if window.is_visible().is_ok_and(|x| x) {
+                        let _ = window.hide();
+                    } else {
+                        let _ = window.show();
+                        let _ = window.center();
+                    }
+                }
+            }
+            "copy_received_text" => p2p::texts::copy(app),
+            "open_received_link" => p2p::texts::open_link(app),
+            "check_for_update" => {
+                if let Some(window) = app.get_webview_window("main") {
+                    let _ = window.emit("e_check_for_update", json!({}));
+                }
+            }
+            "quit" => {
+                app.cleanup_before_exit();
+                std::process::exit(0);
+            }
+            id => {
+                if let Some(transfer_id) = id.strip_prefix("cancel_transfer:") {
+                    app.state::<p2p::transfers::Transfers>()
+                        .send(transfer_id, p2p::transfers::TransferCommand::Cancel);
+                }
+            }
+        })
+        .on_tray_icon_event(|tray, event| {
+            if let TrayIconEvent::Click {
+                button: MouseButton::Left,
+                button_state: MouseButtonState::Up,
+                ..
+            } = event
+            {
+                let app = tray.app_handle();
+                show_main_window(app);
+            }
+        })
+        .tooltip("Isaac Utils")
+        .icon(include_image!("./icons/icon.png"))
+        .build(app)?;
+
+    Ok(())
+}
+
+fn show_main_window(app: &AppHandle) {
+    if let Some(window) = app.get_webview_window("main") {
+        let _ = window.show();
+        let _ = window.set_focus();
+    }
+}
+
+#[cfg_attr(mobile, tauri::mobile_entry_point)]
+pub fn run() {
+    tauri::Builder::default()
+        .plugin(tauri_plugin_process::init())
+        .plugin(tauri_plugin_notification::init())
+        .plugin(tauri_plugin_dialog::init())
+        .plugin(tauri_plugin_updater::Builder::new().build())
+        .plugin(tauri_plugin_fs::init())
+        .plugin(tauri_plugin_shell::init())
+        .plugin(tauri_plugin_clipboard_manager::init())
+        .plugin(tauri_plugin_global_shortcut::Builder::new().build())
+        .plugin(tauri_plugin_single_instance::init(|app, _args, _cwd| {
+            let _ = show_main_window(app);
+        }))
+        .manage(p2p::peers::Peers::default())
+        .manage(p2p::clipboard::ClipboardSync::default())
+        .manage(p2p::transfers::Transfers::default())
+        .manage(p2p::limiter::RateLimiter::default())
+        .manage(p2p::server::ListenState::default())
+        .manage(p2p::discovery::Discovery::default())
+        .manage(p2p::access::AccessControl::default())
+        .manage(p2p::web::Web::default())
+        .manage(p2p::texts::ReceivedText::default())
+        .manage(p2p::card::PairingTokens::default())
+        .invoke_handler(tauri::generate_handler![
+            c_unix_to_readable,
+            c_copy,
+            c_valid_shortcut,
+            c_check_ports,
+            c_p2p_send_file,
+            c_p2p_send_folder,
+            c_p2p_send_text,
+            c_p2p_send_selection,
+            c_p2p_clipboard_sync,
+            c_p2p_device_clipboard_sync,
+            c_p2p_transfers,
+            c_p2p_pause_transfer,
+            c_p2p_resume_transfer,
+            c_p2p_cancel_transfer,
+            c_p2p_set_transfer_rate_limit,
+            c_p2p_history,
+            c_p2p_clear_history,
+            c_p2p_export_history,
+            c_p2p_listen_status,
+            c_p2p_reload_config,
+            c_p2p_share_file,
+            c_p2p_unshare_file,
+            c_p2p_share_link,
+            c_p2p_shared_files,
+            c_p2p_discovered,
+            c_p2p_peers,
+            c_p2p_disconnect,
+            c_p2p_pair,
+            c_p2p_pair_link,
+            c_p2p_connection_card,
+            c_p2p_copy_connection_card,
+            c_p2p_devices,
+            c_p2p_forget_device
+        ])
+        .setup(|app| {
+            let _ = make_tray(&app);
+
+            let config_dir = app.path().app_config_dir()?;
+            app.manage(p2p::devices::Devices::load(&config_dir));
+            app.manage(p2p::config::Config::load(&config_dir));
+            app.manage(p2p::history::History::load(&app.path().app_data_dir()?));
+            tauri::async_runtime::spawn(p2p::server::listen(app.handle().clone()));
+            p2p::discovery::browse(app.handle().clone());
+
+            // Uncomment below to automatically open devtools for the unix popup window.
+            // #[cfg(debug_assertions)]
+            // {
+            //     if let Some(window) = app.get_webview_window("unix_popup") {
+            //         window.open_devtools();
+            //     }
+            // }
+
+            Ok(())
+        })
+        .on_window_event(|window, event| match event {
+            tauri::WindowEvent::Focused(focused) => {
+                // If not the main window and not focused, hide it.
+                if window.label() == "unix_popup" && !focused {
+                    let _ = window.hide();
+                }
+            }
+            tauri::WindowEvent::CloseRequested { api, .. } => {
+                // If the window is attempting to close, prevent and just hide.
+                api.prevent_close();
+                let _ = window.hide();
+            }
+            _ => {}
+        })
+        .build(tauri::generate_context!())
+        .expect("error while running tauri application")
+        .run(|_app_handle, event| match event {
+            tauri::RunEvent::ExitRequested { api, .. } => api.prevent_exit(),
+            _ => {}
+        });
+}
@@ -0,0 +1,289 @@
+use super::config::P2pConfig;
+use ipnet::IpNet;
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The window in which connections from a single address are counted.
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// The allowed and denied networks of the config, parsed once when it is read.
+#[derive(Clone, Debug, Default)]
+pub struct Networks {
+    pub allowed: Vec<IpNet>,
+    pub denied: Vec<IpNet>,
+}
+
+impl Networks {
+    /// Parses the networks in the config (in CIDR notation, or single addresses), returning them
+    /// and an error for each invalid one, which is left out.
+    pub fn parse(config: &P2pConfig) -> (Self, Vec<String>) {
+        let mut errors = Vec::new();
+        let mut parse = |setting: &str, list: &[String]| {
+            let mut networks = Vec::new();
+            for net in list {
+                match net
+                    .parse::<IpNet>()
+                    .or_else(|_| net.parse::<IpAddr>().map(IpNet::from))
+                {
+                    Ok(parsed) => networks.push(parsed),
+                    Err(_) => errors.push(format!("Invalid network in {}: {}", setting, net)),
+                }
+            }
+            networks
+        };
+
+        let networks = Self {
+            allowed: parse("allowedNetworks", &config.allowed_networks),
+            denied: parse("deniedNetworks", &config.denied_networks),
+        };
+        (networks, errors)
+    }
+}
+
+/// Decides which incoming connections get to the WebSocket handshake.
+#[derive(Default)]
+pub struct AccessControl {
+    /// When each address last connected, within the rate window.
+    attempts: Mutex<HashMap<IpAddr, VecDeque<Instant>>>,
+    /// How many connections are currently open.
+    open: Arc<Mutex<u32>>,
+}
+
+/// An admitted connection, counted as open until dropped.
+pub struct Admission {
+    open: Arc<Mutex<u32>>,
+}
+
+impl Drop for Admission {
+    fn drop(&mut self) {
+        *self.open.lock().unwrap() -= 1;
+    }
+}
+
+impl AccessControl {
+    /// Checks a new connection from the given address against the config, returning why it is
+    /// rejected, or the admission to keep for as long as the connection is open.
+    pub fn admit(&self, ip: IpAddr, config: &P2pConfig) -> Result<Admission, String> {
+        // IPv4 peers of a dual-stack listener show up as IPv4-mapped IPv6 addresses.
+        let ip = ip.to_canonical();
+
+        // An allow list of only invalid networks still allows no one.
+        let networks = &config.networks;
+        if networks.denied.iter().any(|net| net.contains(&ip)) {
+            return Err("The address is denied.".to_string());
+        }
+        if !config.allowed_networks.is_empty()
+            && !networks.allowed.iter().any(|net| net.contains(&ip))
+        {
+            return Err("The address is not allowed.".to_string());
+        }
+
+        // Every attempt counts, so a peer that keeps retrying stays limited. Only the addresses
+        // with attempts in the window are kept, and no more attempts than it takes to go over
+        // the limit, so a flood of connections can't grow them.
+        let mut attempts = self.attempts.lock().unwrap();
+        if config.max_connections_per_minute == 0 {
+            attempts.clear();
+        } else {
+            let limit = config.max_connections_per_minute as usize;
+            let now = Instant::now();
+            attempts.retain(|_, times| {
+                while times
+                    .front()
+                    .is_some_and(|time| now.duration_since(*time) > RATE_WINDOW)
+                {
+                    times.pop_front();
+                }
+                !times.is_empty()
+            });
+
+            let times = attempts.entry(ip).or_default();
+            times.push_back(now);
+            // The limit may have been lowered since the older attempts.
+            while times.len() > limit + 1 {
+                times.pop_front();
+            }
+            if times.len() > limit {
+                return Err("Too many connections from the address.".to_string());
+            }
+        }
+        drop(attempts);
+
+        let mut open = self.open.lock().unwrap();
+        if config.max_connections > 0 && *open >= config.max_connections {
+            return Err("Too many open connections.".to_string());
+        }
+        *open += 1;
+
+        Ok(Admission {
+            open: self.open.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn networks_config(allowed: &[&str], denied: &[&str]) -> P2pConfig {
+        let mut config = P2pConfig {
+            allowed_networks: allowed.iter().map(|net| net.to_string()).collect(),
+            denied_networks: denied.iter().map(|net| net.to_string()).collect(),
+            max_connections_per_minute: 0,
+            max_connections: 0,
+            ..P2pConfig::default()
+        };
+        config.networks = Networks::parse(&config).0;
+        config
+    }
+
+    fn admits(config: &P2pConfig, ip: &str) -> bool {
+        AccessControl::default()
+            .admit(ip.parse().unwrap(), config)
+            .is_ok()
+    }
+
+    #[test]
+    fn admit_allows_anyone_without_networks() {
+        let config = networks_config(&[], &[]);
+        assert!(admits(&config, "192.168.1.2"));
+        assert!(admits(&config, "2001:db8::1"));
+    }
+
+    #[test]
+    fn admit_checks_cidr_networks() {
+        let config = networks_config(&["192.168.1.0/24", "fd00::/8"], &[]);
+        assert!(admits(&config, "192.168.1.0"));
+        assert!(admits(&config, "192.168.1.255"));
+        assert!(!admits(&config, "192.168.2.1"));
+        assert!(admits(&config, "fd12::1"));
+        assert!(!admits(&config, "fe80::1"));
+
+        // Host bits set in the network are ignored, like `ip` does.
+        assert!(admits(&networks_config(&["10.1.2.3/8"], &[]), "10.200.0.1"));
+        assert!(admits(&networks_config(&["0.0.0.0/0"], &[]), "8.8.8.8"));
+        assert!(!admits(
+            &networks_config(&["0.0.0.0/0"], &[]),
+            "2001:db8::1"
+        ));
+    }
+
+    #[test]
+    fn admit_takes_single_addresses() {
+        let config = networks_config(&["192.168.1.2", "::1"], &[]);
+        assert!(admits(&config, "192.168.1.2"));
+        assert!(!admits(&config, "192.168.1.3"));
+        assert!(admits(&config, "::1"));
+    }
+
+    #[test]
+    fn admit_denies_before_allowing() {
+        let config = networks_config(&["192.168.0.0/16"], &["192.168.1.0/24"]);
+        assert!(admits(&config, "192.168.2.1"));
+        assert!(!admits(&config, "192.168.1.2"));
+    }
+
+    #[test]
+    fn admit_matches_ipv4_mapped_addresses_as_ipv4() {
+        let config = networks_config(&[], &["192.168.1.0/24"]);
+        assert!(!admits(&config, "::ffff:192.168.1.2"));
+        assert!(admits(&config, "::ffff:192.168.2.1"));
+    }
+
+    #[test]
+    fn admit_skips_invalid_networks() {
+        // An allow list of only invalid networks still allows no one.
+        let config = networks_config(&["nonsense", "192.168.1.0/33"], &["also nonsense"]);
+        assert!(!admits(&config, "192.168.1.2"));
+
+        let config = networks_config(&["nonsense", "192.168.1.0/24"], &[]);
+        assert!(admits(&config, "192.168.1.2"));
+    }
+
+    #[test]
+    fn parse_reports_invalid_networks() {
+        let config = networks_config(&["nonsense", "10.0.0.0/8"], &["192.168.1.0/33"]);
+        let (networks, errors) = Networks::parse(&config);
+        assert_eq!(networks.allowed.len(), 1);
+        assert!(networks.denied.is_empty());
+        assert_eq!(
+            errors,
+            [
+                "Invalid network in allowedNetworks: nonsense",
+                "Invalid network in deniedNetworks: 192.168.1.0/33"
+            ]
+        );
+    }
+
+    #[test]
+    fn admit_limits_connections_per_minute() {
+        let config = P2pConfig {
+            max_connections_per_minute: 2,
+            ..networks_config(&[], &[])
+        };
+        let access = AccessControl::default();
+        let ip = "192.168.1.2".parse().unwrap();
+        assert!(access.admit(ip, &config).is_ok());
+        assert!(access.admit(ip, &config).is_ok());
+        assert!(access.admit(ip, &config).is_err());
+
+        // Other addresses have their own count.
+        assert!(access
+            .admit("192.168.1.3".parse().unwrap(), &config)
+            .is_ok());
+    }
+
+    #[test]
+    fn admit_keeps_only_the_attempts_it_needs() {
+        let config = P2pConfig {
+            max_connections_per_minute: 2,
+            ..networks_config(&[], &[])
+        };
+        let access = AccessControl::default();
+        let ip = "192.168.1.2".parse().unwrap();
+        for _ in 0..100 {
+            let _ = access.admit(ip, &config);
+        }
+        assert_eq!(access.attempts.lock().unwrap()[&ip].len(), 3);
+        assert!(access.admit(ip, &config).is_err());
+
+        // Addresses whose attempts all expired are dropped.
+        let expired = Instant::now().checked_sub(RATE_WINDOW * 2).unwrap();
+        access
+            .attempts
+            .lock()
+            .unwrap()
+            .get_mut(&ip)
+            .unwrap()
+            .iter_mut()
+            .for_each(|time| *time = expired);
+        assert!(access
+            .admit("192.168.1.3".parse().unwrap(), &config)
+            .is_ok());
+        assert!(!access.attempts.lock().unwrap().contains_key(&ip));
+
+        // Nothing is kept without a limit.
+        let unlimited = networks_config(&[], &[]);
+        assert!(access.admit(ip, &unlimited).is_ok());
+        assert!(access.attempts.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn admit_limits_open_connections() {
+        let config = P2pConfig {
+            max_connections: 2,
+            ..networks_config(&[], &[])
+        };
+        let access = AccessControl::default();
+        let ip = "192.168.1.2".parse().unwrap();
+        let first = access.admit(ip, &config).unwrap();
+        let _second = access.admit(ip, &config).unwrap();
+        assert!(access.admit(ip, &config).is_err());
+
+        // Closing a connection makes room for another.
+        drop(first);
+        assert!(access.admit(ip, &config).is_ok());
+    }
+}
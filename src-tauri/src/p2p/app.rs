@@ -0,0 +1,168 @@
+use super::card::PairingTokens;
+use super::clipboard::{ClipboardSync, Received};
+use super::config::{Config, P2pConfig};
+use super::devices::Devices;
+use super::emit_p2p;
+use super::history::Outcome;
+use super::host::{Host, Transfer};
+use super::limiter;
+use super::peers::Peers;
+use super::receive;
+use super::server::{ListenState, ListenStatus};
+use super::texts;
+use super::transfers::{self, ActiveTransfer, Direction, TransferCommand, Transfers};
+use serde::Serialize;
+use serde_json::json;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use tauri::{AppHandle, EventId, Listener, Manager};
+use tokio::sync::mpsc;
+
+/// A prompt shown in the main window, listening for the user's answer until dropped.
+pub struct AnswerListener {
+    app: AppHandle,
+    id: EventId,
+}
+
+impl Drop for AnswerListener {
+    fn drop(&mut self) {
+        self.app.unlisten(self.id);
+    }
+}
+
+impl Host for AppHandle {
+    type Prompt = AnswerListener;
+    type Transfer = ActiveTransfer;
+
+    fn devices(&self) -> &Devices {
+        self.state::<Devices>().inner()
+    }
+
+    fn p2p_config(&self) -> P2pConfig {
+        self.state::<Config>().get()
+    }
+
+    fn listen_port(&self) -> Option<u16> {
+        match self.state::<ListenState>().get() {
+            ListenStatus::Listening { address, .. } => Some(address.port()),
+            _ => None,
+        }
+    }
+
+    fn receive_dir(&self) -> std::io::Result<PathBuf> {
+        receive::receive_dir(self)
+    }
+
+    fn report(&self, event: &str, data: serde_json::Value) {
+        emit_p2p(self, event, data);
+    }
+
+    fn ask(
+        &self,
+        event: &str,
+        data: serde_json::Value,
+        answers: mpsc::UnboundedSender<bool>,
+    ) -> AnswerListener {
+        // Folders are answered like files.
+        let answer_event = match event {
+            "pair_request" => "e_p2p_pair",
+            "pair_code" => "e_p2p_pair_code",
+            _ => "e_p2p_ask_file",
+        };
+        let id = listen_answer(self, answer_event, &data["peer"], answers);
+        emit_p2p(self, event, data);
+
+        AnswerListener {
+            app: self.clone(),
+            id,
+        }
+    }
+
+    fn log(&self, message: String) {
+        println!("{}", message);
+    }
+
+    fn start_transfer(
+        &self,
+        direction: Direction,
+        name: &str,
+        size: u64,
+        peer: &str,
+    ) -> (ActiveTransfer, mpsc::UnboundedReceiver<TransferCommand>) {
+        transfers::start(self, direction, name, size, peer)
+    }
+
+    fn report_paused(&self, id: &str, paused: bool, by_peer: bool) {
+        transfers::report_paused(self, id, paused, by_peer);
+    }
+
+    fn record_compression_ratio(&self, id: &str, ratio: f64) {
+        self.state::<Transfers>().set_compression_ratio(id, ratio);
+    }
+
+    fn throttle(&self, transfer_id: &str, bytes: u64) -> Duration {
+        limiter::throttle(self, transfer_id, bytes)
+    }
+
+    fn text_received(&self, peer: SocketAddr, text: String, clipboard: bool) {
+        if !clipboard {
+            texts::receive(self, peer, text);
+            return;
+        }
+
+        // Clipboard changes are only copied when clipboard sync is turned on here too, for the
+        // device that sent them.
+        let received = self.state::<ClipboardSync>().receive(
+            &self.state::<Peers>(),
+            self.devices(),
+            peer,
+            &text,
+        );
+        match received {
+            Received::Copied => {
+                emit_p2p(self, "clipboard_received", json!({ "peer": peer }));
+            }
+            Received::NotSynced => texts::receive(self, peer, text),
+            Received::Ignored => {}
+        }
+    }
+
+    fn redeem_token(&self, token: &str) -> bool {
+        self.state::<PairingTokens>().redeem(token)
+    }
+
+    fn identify(&self, peer: SocketAddr, device_id: String, device_name: String) {
+        self.state::<Peers>()
+            .identify(&peer, device_id, device_name);
+    }
+}
+
+impl Transfer for ActiveTransfer {
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn finish(&mut self, outcome: Outcome, sha256: Option<String>, error: Option<String>) {
+        ActiveTransfer::finish(self, outcome, sha256, error);
+    }
+}
+
+/// Listens for the user's answer to a prompt about this peer, sending it through the channel.
+pub(super) fn listen_answer(
+    app: &AppHandle,
+    event: &str,
+    peer: impl Serialize,
+    sender: mpsc::UnboundedSender<bool>,
+) -> EventId {
+    let peer = json!(peer);
+    app.listen(event, move |event| {
+        let payload =
+            serde_json::from_str::<serde_json::Value>(event.payload()).unwrap_or_default();
+        if payload["peer"] != peer {
+            return;
+        }
+
+        let _ = sender.send(payload["accept"].as_bool().unwrap_or(false));
+    })
+}
@@ -0,0 +1,190 @@
+use super::config::Config;
+use super::devices::random_hex;
+use super::server::{ListenState, ListenStatus};
+use arboard::{Clipboard, ImageData};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use image::{DynamicImage, GrayImage, ImageFormat, Luma};
+use qrcode::QrCode;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::io::Cursor;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tauri::{AppHandle, Manager};
+
+/// How long the pairing token of a connection card can be used.
+const TOKEN_LIFETIME: Duration = Duration::from_secs(10 * 60);
+
+/// The smallest size (in pixels) of the QR code, to stay easy to scan from a screen.
+const QR_SIZE: u32 = 320;
+
+/// What to scan to connect to this device: a link to its web page, which the app also takes to
+/// pair.
+///
+/// The pairing token is in the link's fragment, which browsers never send, so it does not go over
+/// the network in plain text.
+#[derive(Serialize)]
+pub struct ConnectionCard {
+    pub link: String,
+    pub address: SocketAddr,
+    /// The QR code of the link, as a base64 encoded PNG.
+    pub png: String,
+}
+
+/// The pairing tokens of the connection cards shown, each good for a single pairing attempt.
+#[derive(Default)]
+pub struct PairingTokens(Mutex<Vec<(String, Instant)>>);
+
+impl PairingTokens {
+    fn issue(&self) -> String {
+        let token = random_hex(16);
+        let mut tokens = self.0.lock().unwrap();
+        tokens.retain(|(_, issued_at)| issued_at.elapsed() < TOKEN_LIFETIME);
+        tokens.push((token.clone(), Instant::now()));
+        token
+    }
+
+    /// Uses up a token (whether or not the pairing then goes through), returning whether it was
+    /// valid.
+    pub fn redeem(&self, token: &str) -> bool {
+        let mut tokens = self.0.lock().unwrap();
+        tokens.retain(|(_, issued_at)| issued_at.elapsed() < TOKEN_LIFETIME);
+        let len = tokens.len();
+        tokens.retain(|(t, _)| t != token);
+        tokens.len() != len
+    }
+}
+
+/// Makes a connection card with a new pairing token, for the address the server listens on.
+pub fn card(app: &AppHandle) -> Result<ConnectionCard, String> {
+    let (link, address) = link(app)?;
+
+    let mut png = Vec::new();
+    DynamicImage::ImageLuma8(render(&link)?)
+        .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+        .map_err(|e| e.to_string())?;
+
+    Ok(ConnectionCard {
+        link,
+        address,
+        png: STANDARD.encode(png),
+    })
+}
+
+/// Copies the QR code of a new connection card to the clipboard, returning its link.
+pub fn copy(app: &AppHandle) -> Result<String, String> {
+    let (link, _) = link(app)?;
+    let image = DynamicImage::ImageLuma8(render(&link)?).to_rgba8();
+
+    let mut clipboard = Clipboard::new().map_err(|e| e.to_string())?;
+    clipboard
+        .set_image(ImageData {
+            width: image.width() as usize,
+            height: image.height() as usize,
+            bytes: Cow::Owned(image.into_raw()),
+        })
+        .map_err(|e| e.to_string())?;
+
+    Ok(link)
+}
+
+/// Reads a connection card's link, returning the address to pair with and the token.
+pub fn parse_link(link: &str) -> Option<(String, String)> {
+    let rest = link.trim().strip_prefix("http://")?;
+    let (address, rest) = rest.split_once('/')?;
+    let (_, fragment) = rest.split_once('#')?;
+    let token = fragment
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("pair="))
+        .filter(|token| !token.is_empty())?;
+
+    Some((address.to_string(), token.to_string()))
+}
+
+/// Issues a pairing token, returning the card's link with it and the address it points to.
+///
+/// The link opens the web page when scanned with a phone, so there is no card without it.
+fn link(app: &AppHandle) -> Result<(String, SocketAddr), String> {
+    if !app.state::<Config>().get().web_page {
+        return Err(
+            "Turn on the web page to show a connection card, its link opens it.".to_string(),
+        );
+    }
+    let address = address(app)?;
+    let token = app.state::<PairingTokens>().issue();
+    Ok((format!("http://{}/#pair={}", address, token), address))
+}
+
+/// Returns the address other devices can reach the server at.
+pub fn address(app: &AppHandle) -> Result<SocketAddr, String> {
+    let ListenStatus::Listening { address, .. } = app.state::<ListenState>().get() else {
+        return Err("The P2P server is not listening.".to_string());
+    };
+
+    // Listening on all interfaces, the default one is where others are most likely to be. Its
+    // IPv4 address is taken first, or else a global IPv6 one (a link-local one would need a scope
+    // the other device can't know).
+    if address.ip().is_unspecified() {
+        let interface = netdev::get_default_interface()?;
+        let ip = interface
+            .ipv4
+            .first()
+            .map(|net| IpAddr::V4(net.addr))
+            .or_else(|| {
+                interface
+                    .ipv6
+                    .iter()
+                    .find(|net| !net.addr.is_unicast_link_local())
+                    .map(|net| IpAddr::V6(net.addr))
+            })
+            .ok_or("The default network interface has no usable IP address.")?;
+        Ok(SocketAddr::new(ip, address.port()))
+    } else {
+        Ok(address)
+    }
+}
+
+/// Renders a QR code of the text.
+fn render(text: &str) -> Result<GrayImage, String> {
+    let code = QrCode::new(text.as_bytes()).map_err(|e| e.to_string())?;
+
+    Ok(code
+        .render::<Luma<u8>>()
+        .quiet_zone(true)
+        .min_dimensions(QR_SIZE, QR_SIZE)
+        .build())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_link_reads_the_address_and_token() {
+        assert_eq!(
+            parse_link("http://192.168.1.2:15446/#pair=abc123"),
+            Some(("192.168.1.2:15446".to_string(), "abc123".to_string()))
+        );
+        assert_eq!(
+            parse_link("  http://[fe80::1]:15446/#other=1&pair=abc123\n"),
+            Some(("[fe80::1]:15446".to_string(), "abc123".to_string()))
+        );
+    }
+
+    #[test]
+    fn parse_link_refuses_other_links() {
+        for link in [
+            "",
+            "192.168.1.2:15446/#pair=abc123",
+            "https://192.168.1.2:15446/#pair=abc123",
+            "http://192.168.1.2:15446",
+            "http://192.168.1.2:15446/",
+            "http://192.168.1.2:15446/#pair=",
+            "http://192.168.1.2:15446/#token=abc123",
+            "http://192.168.1.2:15446/?pair=abc123",
+        ] {
+            assert_eq!(parse_link(link), None, "{:?}", link);
+        }
+    }
+}
@@ -0,0 +1,1003 @@
+use super::compression::{self, COMPRESSIONS};
+use super::config::P2pConfig;
+use super::devices::{auth_proof, pairing_code, random_hex};
+use super::history::Outcome;
+use super::host::{Host, Transfer};
+use super::incoming::HANDSHAKE_TIMEOUT;
+use super::limiter::RECHECK_INTERVAL;
+use super::protocol::{
+    connect_ws, next_message, read_message, with_default_port, Capability, Compression, ErrorCode,
+    ManifestEntry, Negotiated, P2pMessage, PROTOCOL_VERSION,
+};
+use super::secure::{self, SecureReceiver, SecureSender, Session};
+use super::transfers::{Direction, TransferCommand};
+use futures::{SinkExt, StreamExt};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::{Message, Result};
+
+/// The size of each binary frame sent to a peer.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// How long to wait for the peer to accept or decline a file or pairing (a bit over the receiver's own timeout).
+const ASK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(70);
+
+/// How long a probed peer has to say who it is.
+const PROBE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(3);
+
+/// Why pairing failed when the peer stopped answering.
+const PAIR_TIMED_OUT: &str = "The pairing timed out.";
+
+/// How many times an interrupted transfer is resumed before giving up.
+const MAX_ATTEMPTS: u32 = 3;
+
+/// How long to wait before reconnecting after a transfer was interrupted.
+const RETRY_DELAY: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// Why a request to a peer failed, so a refusal can be told apart from an error.
+#[derive(Debug)]
+pub enum PeerError {
+    Failed(String),
+    /// The peer (or one of the users) declined.
+    Declined(String),
+    /// The devices are not paired, or the peer doesn't recognize us.
+    Unpaired(String),
+    /// The peer stopped answering in the middle of the handshake.
+    TimedOut(String),
+}
+
+impl fmt::Display for PeerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerError::Failed(e)
+            | PeerError::Declined(e)
+            | PeerError::Unpaired(e)
+            | PeerError::TimedOut(e) => f.write_str(e),
+        }
+    }
+}
+
+impl From<String> for PeerError {
+    fn from(e: String) -> Self {
+        PeerError::Failed(e)
+    }
+}
+
+impl From<&str> for PeerError {
+    fn from(e: &str) -> Self {
+        PeerError::Failed(e.to_string())
+    }
+}
+
+impl From<PeerError> for String {
+    fn from(e: PeerError) -> Self {
+        e.to_string()
+    }
+}
+
+/// Why sending a file failed.
+enum SendError {
+    /// The transfer can't go ahead (declined, handshake failed, hash mismatch...).
+    Fatal(PeerError),
+    /// The connection dropped (or the handshake timed out), so the transfer can be resumed.
+    Interrupted(String),
+    /// The user cancelled the transfer.
+    Cancelled,
+}
+
+impl From<PeerError> for SendError {
+    fn from(e: PeerError) -> Self {
+        match e {
+            PeerError::TimedOut(e) => SendError::Interrupted(e),
+            e => SendError::Fatal(e),
+        }
+    }
+}
+
+/// How the app controls a transfer while it runs.
+struct Controls<'a> {
+    id: &'a str,
+    commands: &'a mut mpsc::UnboundedReceiver<TransferCommand>,
+}
+
+/// A file being sent to a peer.
+struct OutgoingFile<'a> {
+    path: &'a Path,
+    name: String,
+    size: u64,
+    sha256: String,
+}
+
+/// A folder being sent to a peer, with each file's path on disk and in the manifest.
+struct OutgoingFolder {
+    name: String,
+    files: Vec<(PathBuf, ManifestEntry)>,
+}
+
+/// What is being sent, so files and folders are retried (and reported) the same way.
+enum Outgoing<'a> {
+    File(&'a OutgoingFile<'a>),
+    Folder(&'a OutgoingFolder),
+}
+
+impl Outgoing<'_> {
+    fn name(&self) -> &str {
+        match self {
+            Outgoing::File(file) => &file.name,
+            Outgoing::Folder(folder) => &folder.name,
+        }
+    }
+
+    /// The content hash, for files (a folder's files each have their own).
+    fn sha256(&self) -> Option<String> {
+        match self {
+            Outgoing::File(file) => Some(file.sha256.clone()),
+            Outgoing::Folder(_) => None,
+        }
+    }
+
+    fn size(&self) -> u64 {
+        match self {
+            Outgoing::File(file) => file.size,
+            Outgoing::Folder(folder) => folder.files.iter().map(|(_, e)| e.size).sum(),
+        }
+    }
+
+    /// Returns the payload of an event about this transfer, with the given extra fields.
+    fn event(&self, address: &str, extra: serde_json::Value) -> serde_json::Value {
+        let mut event = match self {
+            Outgoing::File(file) => json!({"file_name": file.name, "file_size": file.size}),
+            Outgoing::Folder(folder) => json!({
+                "folder_name": folder.name,
+                "file_count": folder.files.len(),
+                "total_size": self.size()
+            }),
+        };
+        event["peer"] = json!(address);
+        if let (Some(event), serde_json::Value::Object(extra)) = (event.as_object_mut(), extra) {
+            event.extend(extra);
+        }
+
+        event
+    }
+}
+
+/// Sends a file to the peer at the given address (`host` or `host:port`).
+///
+/// If the connection drops mid-transfer, it reconnects and resumes from what the peer already has.
+pub async fn send_file<H: Host>(host: &H, address: &str, path: &Path) -> Result<(), PeerError> {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or("Invalid file path.")?
+        .to_string();
+    let size = tokio::fs::metadata(path)
+        .await
+        .map_err(|e| e.to_string())?
+        .len();
+
+    let address = with_default_port(address);
+
+    // Hash the content so the peer can verify it and resume partial transfers.
+    host.report(
+        "send_hashing",
+        json!({"file_name": name, "file_size": size, "peer": address}),
+    );
+    let sha256 = hash_file(path).await.map_err(|e| e.to_string())?;
+    let outgoing = OutgoingFile {
+        path,
+        name,
+        size,
+        sha256,
+    };
+
+    send_with_retries(host, &address, Outgoing::File(&outgoing)).await
+}
+
+/// Sends a folder and everything in it to the peer at the given address (`host` or `host:port`).
+///
+/// The peer accepts the whole folder at once, then its files are sent one after the other.
+pub async fn send_folder<H: Host>(host: &H, address: &str, path: &Path) -> Result<(), PeerError> {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or("Invalid folder path.")?
+        .to_string();
+
+    let address = with_default_port(address);
+
+    host.report(
+        "send_hashing",
+        json!({"folder_name": name, "peer": address}),
+    );
+    let files = list_folder(path).await.map_err(|e| e.to_string())?;
+    let outgoing = OutgoingFolder { name, files };
+
+    send_with_retries(host, &address, Outgoing::Folder(&outgoing)).await
+}
+
+/// Sends a file or folder, reconnecting and resuming when the connection drops.
+async fn send_with_retries<H: Host>(
+    host: &H,
+    address: &str,
+    outgoing: Outgoing<'_>,
+) -> Result<(), PeerError> {
+    let (mut transfer, mut commands) =
+        host.start_transfer(Direction::Send, outgoing.name(), outgoing.size(), address);
+    let mut controls = Controls {
+        id: transfer.id(),
+        commands: &mut commands,
+    };
+
+    let mut attempt = 1;
+    loop {
+        let result = match outgoing {
+            Outgoing::File(file) => send_file_once(host, address, file, &mut controls).await,
+            Outgoing::Folder(folder) => {
+                send_folder_once(host, address, folder, &mut controls).await
+            }
+        };
+
+        let error = match result {
+            Ok(()) => {
+                transfer.finish(Outcome::Completed, outgoing.sha256(), None);
+                host.report(
+                    "send_complete",
+                    outgoing.event(address, json!({"transfer_id": transfer.id()})),
+                );
+                return Ok(());
+            }
+            Err(SendError::Cancelled) => {
+                transfer.finish(Outcome::Cancelled, outgoing.sha256(), None);
+                host.report(
+                    "send_cancelled",
+                    outgoing.event(address, json!({"transfer_id": transfer.id()})),
+                );
+                return Err("The transfer was cancelled.".into());
+            }
+            Err(SendError::Interrupted(e)) if attempt < MAX_ATTEMPTS => {
+                host.log(format!(
+                    "Transfer to {} interrupted, retrying: {}",
+                    address, e
+                ));
+                host.report(
+                    "send_retrying",
+                    outgoing.event(
+                        address,
+                        json!({"transfer_id": transfer.id(), "attempt": attempt, "error": e}),
+                    ),
+                );
+                attempt += 1;
+                tokio::time::sleep(RETRY_DELAY).await;
+                continue;
+            }
+            Err(SendError::Interrupted(e)) => PeerError::Failed(e),
+            Err(SendError::Fatal(e)) => e,
+        };
+
+        transfer.finish(Outcome::Failed, outgoing.sha256(), Some(error.to_string()));
+        host.report(
+            "send_failed",
+            outgoing.event(
+                address,
+                json!({"transfer_id": transfer.id(), "error": error.to_string()}),
+            ),
+        );
+        return Err(error);
+    }
+}
+
+async fn send_file_once<H: Host>(
+    host: &H,
+    address: &str,
+    outgoing: &OutgoingFile<'_>,
+    controls: &mut Controls<'_>,
+) -> Result<(), SendError> {
+    // Connect, making sure the peer can receive files.
+    let (mut ws_sender, mut ws_receiver, negotiated) =
+        connect(host, address).await.map_err(SendError::from)?;
+    if !negotiated.capabilities.contains(&Capability::File) {
+        let _ = ws_sender.close().await;
+        return Err(SendError::Fatal("The peer does not accept files.".into()));
+    }
+
+    let result = transfer(
+        host,
+        address,
+        &mut ws_sender,
+        &mut ws_receiver,
+        outgoing,
+        controls,
+        negotiated.compression,
+    )
+    .await;
+    let _ = ws_sender.close().await;
+    result
+}
+
+async fn send_folder_once<H: Host>(
+    host: &H,
+    address: &str,
+    folder: &OutgoingFolder,
+    controls: &mut Controls<'_>,
+) -> Result<(), SendError> {
+    // Connect, making sure the peer can receive folders.
+    let (mut ws_sender, mut ws_receiver, negotiated) =
+        connect(host, address).await.map_err(SendError::from)?;
+    if !negotiated.capabilities.contains(&Capability::Folder) {
+        let _ = ws_sender.close().await;
+        return Err(SendError::Fatal("The peer does not accept folders.".into()));
+    }
+
+    // Announce the folder with everything in it.
+    ws_sender
+        .send(
+            P2pMessage::FolderOffer {
+                name: folder.name.clone(),
+                entries: folder.files.iter().map(|(_, e)| e.clone()).collect(),
+            }
+            .into(),
+        )
+        .await
+        .map_err(|e| SendError::Fatal(e.to_string().into()))?;
+    let event = Outgoing::Folder(folder).event(address, json!({"transfer_id": controls.id}));
+    host.report("send_waiting", event.clone());
+
+    // Wait for the peer to accept or decline.
+    let answer = tokio::time::timeout(ASK_TIMEOUT, next_message(&mut ws_receiver))
+        .await
+        .unwrap_or(Err("Timed out waiting for the peer to accept.".to_string()))
+        .map_err(|e| SendError::Fatal(e.into()))?;
+    if !matches!(answer, P2pMessage::FileAnswer { accept: true, .. }) {
+        host.report("send_declined", event);
+        let _ = ws_sender.close().await;
+        return Err(SendError::Fatal(PeerError::Declined(
+            "The peer declined the folder.".to_string(),
+        )));
+    }
+
+    // Send the files in manifest order, the peer accepts each one without asking.
+    let mut result = Ok(());
+    for (path, entry) in &folder.files {
+        let outgoing = OutgoingFile {
+            path,
+            name: entry.path.clone(),
+            size: entry.size,
+            sha256: entry.sha256.clone(),
+        };
+        result = transfer(
+            host,
+            address,
+            &mut ws_sender,
+            &mut ws_receiver,
+            &outgoing,
+            controls,
+            negotiated.compression,
+        )
+        .await;
+        if result.is_err() {
+            break;
+        }
+    }
+
+    let _ = ws_sender.close().await;
+    result
+}
+
+/// Pings the peer while we wait on it, so a connection that died without closing is noticed: a
+/// peer sending nothing back (not even pongs) for `max_missed_pongs` pings in a row is given up on.
+struct Keepalive {
+    ping: tokio::time::Interval,
+    /// The pings sent since the peer last sent anything.
+    missed: u32,
+    max_missed: u32,
+}
+
+impl Keepalive {
+    fn new(config: &P2pConfig) -> Self {
+        let interval = std::time::Duration::from_secs(config.ping_interval.max(1));
+        Self {
+            ping: tokio::time::interval_at(tokio::time::Instant::now() + interval, interval),
+            missed: 0,
+            max_missed: config.max_missed_pongs.max(1),
+        }
+    }
+
+    /// Pings the peer, failing if it left too many pings unanswered already.
+    async fn send_ping(&mut self, ws_sender: &mut SecureSender) -> Result<(), String> {
+        if self.missed >= self.max_missed {
+            return Err("The peer stopped answering pings.".to_string());
+        }
+
+        ws_sender
+            .send(Message::Ping(Vec::new()))
+            .await
+            .map_err(|e| e.to_string())?;
+        self.missed += 1;
+        Ok(())
+    }
+}
+
+/// Waits for the next protocol message like `next_message`, taking anything the peer sends (pongs
+/// included) as a sign it is still there.
+async fn next_message_alive(
+    ws_receiver: &mut SecureReceiver,
+    missed: &mut u32,
+) -> Result<P2pMessage, String> {
+    next_message(&mut (&mut *ws_receiver).inspect(|_| *missed = 0)).await
+}
+
+/// Offers a file on an open connection and streams it from wherever the peer wants it, waiting
+/// for the peer to confirm it received (and verified) everything.
+async fn transfer<H: Host>(
+    host: &H,
+    address: &str,
+    ws_sender: &mut SecureSender,
+    ws_receiver: &mut SecureReceiver,
+    outgoing: &OutgoingFile<'_>,
+    controls: &mut Controls<'_>,
+    compression: Option<Compression>,
+) -> Result<(), SendError> {
+    let fatal = |e: String| SendError::Fatal(e.into());
+    let interrupted =
+        |e: tokio_tungstenite::tungstenite::Error| SendError::Interrupted(e.to_string());
+
+    // Announce the file, compressed unless it already is.
+    let compression = compression.filter(|_| compression::is_compressible(&outgoing.name));
+    ws_sender
+        .send(
+            P2pMessage::FileOffer {
+                name: outgoing.name.clone(),
+                size: outgoing.size,
+                sha256: outgoing.sha256.clone(),
+                compression,
+            }
+            .into(),
+        )
+        .await
+        .map_err(|e| SendError::Fatal(e.to_string().into()))?;
+    host.report(
+        "send_waiting",
+        json!({
+            "transfer_id": controls.id,
+            "file_name": outgoing.name,
+            "file_size": outgoing.size,
+            "peer": address
+        }),
+    );
+
+    // Wait for the peer to accept or decline.
+    let answer = tokio::time::timeout(ASK_TIMEOUT, next_message(ws_receiver))
+        .await
+        .unwrap_or(Err("Timed out waiting for the peer to accept.".to_string()))
+        .map_err(fatal)?;
+    let offset = match answer {
+        P2pMessage::FileAnswer {
+            accept: true,
+            offset,
+        } if offset <= outgoing.size => offset,
+        _ => {
+            host.report(
+                "send_declined",
+                json!({"transfer_id": controls.id, "file_name": outgoing.name, "peer": address}),
+            );
+            return Err(SendError::Fatal(PeerError::Declined(
+                "The peer declined the file.".to_string(),
+            )));
+        }
+    };
+
+    // Stream the file in chunks, starting from whatever the peer already has.
+    let mut file = File::open(outgoing.path)
+        .await
+        .map_err(|e| SendError::Fatal(e.to_string().into()))?;
+    file.seek(SeekFrom::Start(offset))
+        .await
+        .map_err(|e| SendError::Fatal(e.to_string().into()))?;
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut sent = offset;
+    let mut wire_sent = 0;
+    let mut paused = false;
+    let mut send_at = tokio::time::Instant::now();
+    let mut keepalive = Keepalive::new(&host.p2p_config());
+    // Nothing is left to send when the peer already has the whole file, and it may already have
+    // sent the result.
+    while sent < outgoing.size {
+        tokio::select! {
+            biased;
+
+            // The transfer can be paused, resumed or cancelled from here...
+            Some(command) = controls.commands.recv() => {
+                ws_sender.send(P2pMessage::TransferControl { action: command }.into()).await.map_err(interrupted)?;
+                if command == TransferCommand::Cancel {
+                    return Err(SendError::Cancelled);
+                }
+
+                paused = command == TransferCommand::Pause;
+                host.report_paused(controls.id, paused, false);
+            }
+
+            // ...or by the peer.
+            msg = next_message_alive(ws_receiver, &mut keepalive.missed) => match msg {
+                Ok(P2pMessage::TransferControl { action: TransferCommand::Cancel }) => {
+                    return Err(fatal("The peer cancelled the transfer.".to_string()));
+                }
+                Ok(P2pMessage::TransferControl { action }) => {
+                    paused = action == TransferCommand::Pause;
+                    host.report_paused(controls.id, paused, true);
+                }
+                Ok(_) => return Err(fatal("The peer failed receiving the file.".to_string())),
+                Err(e) => return Err(SendError::Interrupted(e)),
+            },
+
+            // Send the next chunk once the rate limits allow it, checking them again while
+            // waiting so changing them applies right away.
+            _ = tokio::time::sleep_until(send_at.min(tokio::time::Instant::now() + RECHECK_INTERVAL)), if !paused => {
+                let now = tokio::time::Instant::now();
+                if now < send_at {
+                    send_at = now + host.throttle(controls.id, 0);
+                    continue;
+                }
+
+                let read = file
+                    .read(&mut buffer)
+                    .await
+                    .map_err(|e| SendError::Fatal(e.to_string().into()))?;
+                if read == 0 {
+                    break;
+                }
+
+                let frame = compression::encode_chunk(compression, &buffer[..read]);
+                let frame_len = frame.len() as u64;
+                ws_sender
+                    .send(Message::Binary(frame))
+                    .await
+                    .map_err(interrupted)?;
+                sent += read as u64;
+                wire_sent += frame_len;
+                send_at = tokio::time::Instant::now() + host.throttle(controls.id, frame_len);
+
+                // The ratio is only worth showing for compressed data.
+                let ratio = compression.map(|_| compression::ratio(sent - offset, wire_sent));
+                if let Some(ratio) = ratio {
+                    host.record_compression_ratio(controls.id, ratio);
+                }
+                host.report("send_progress", json!({
+                    "transfer_id": controls.id,
+                    "file_name": outgoing.name,
+                    "file_size": outgoing.size,
+                    "sent": sent,
+                    "compression_ratio": ratio,
+                    "peer": address
+                }));
+            }
+
+            // Nothing may come from the peer for a long time (like while paused), so make sure
+            // it is still there.
+            _ = keepalive.ping.tick() => {
+                keepalive.send_ping(ws_sender).await.map_err(SendError::Interrupted)?;
+            }
+        }
+    }
+
+    // Wait for the peer to confirm it received (and verified) everything, which can take a while
+    // for a big file.
+    loop {
+        tokio::select! {
+            msg = next_message_alive(ws_receiver, &mut keepalive.missed) => return match msg {
+                Ok(P2pMessage::FileResult { success: true }) => Ok(()),
+                Ok(_) => Err(fatal("The peer failed receiving the file.".to_string())),
+                Err(e) => Err(SendError::Interrupted(e)),
+            },
+            _ = keepalive.ping.tick() => {
+                keepalive.send_ping(ws_sender).await.map_err(SendError::Interrupted)?;
+            }
+        }
+    }
+}
+
+/// Returns the hex encoded SHA-256 of a file's content.
+pub async fn hash_file(path: &Path) -> std::io::Result<String> {
+    let mut file = File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; CHUNK_SIZE];
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+
+        hasher.update(&buffer[..read]);
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Sends text to the peer at the given address, to show it or (for clipboard sync) to copy it.
+pub async fn send_text<H: Host>(
+    host: &H,
+    address: &str,
+    text: String,
+    clipboard: bool,
+) -> Result<(), PeerError> {
+    let mut connection = TextConnection::open(host, address).await?;
+    let result = connection.send(text, clipboard).await;
+    connection.close().await;
+    result.map_err(PeerError::from)
+}
+
+/// A connection to a peer that accepts text, to send several texts over.
+pub struct TextConnection {
+    ws_sender: SecureSender,
+    ws_receiver: SecureReceiver,
+    negotiated: Negotiated,
+    keepalive: Keepalive,
+}
+
+impl TextConnection {
+    pub async fn open<H: Host>(host: &H, address: &str) -> Result<Self, PeerError> {
+        let address = with_default_port(address);
+        let (mut ws_sender, ws_receiver, negotiated) = connect(host, &address).await?;
+        if !negotiated.capabilities.contains(&Capability::Text) {
+            let _ = ws_sender.close().await;
+            return Err("The peer does not accept text.".into());
+        }
+
+        Ok(Self {
+            ws_sender,
+            ws_receiver,
+            negotiated,
+            keepalive: Keepalive::new(&host.p2p_config()),
+        })
+    }
+
+    pub async fn send(&mut self, text: String, clipboard: bool) -> Result<(), String> {
+        // Long texts are compressed, if that makes them smaller.
+        let compression = self.negotiated.compression;
+        let message = match compression::compress_text(compression, &text) {
+            Some(compressed) => P2pMessage::Text {
+                text: compressed,
+                clipboard,
+                compression,
+            },
+            None => P2pMessage::Text {
+                text,
+                clipboard,
+                compression: None,
+            },
+        };
+        self.ws_sender
+            .send(message.into())
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    /// Waits for the peer to close the connection (or stop answering pings), answering its pings
+    /// in the meantime.
+    pub async fn closed(&mut self) {
+        loop {
+            tokio::select! {
+                msg = self.ws_receiver.next() => match msg {
+                    Some(Ok(msg)) if !msg.is_close() => self.keepalive.missed = 0,
+                    _ => break,
+                },
+                _ = self.keepalive.ping.tick() => {
+                    if self.keepalive.send_ping(&mut self.ws_sender).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    pub async fn close(mut self) {
+        let _ = self.ws_sender.close().await;
+    }
+}
+
+/// Lists the files in a folder and its sub folders (not following symlinks), hashing each one.
+async fn list_folder(root: &Path) -> std::io::Result<Vec<(PathBuf, ManifestEntry)>> {
+    let mut files = vec![];
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let mut read_dir = tokio::fs::read_dir(&dir).await?;
+        while let Some(entry) = read_dir.next_entry().await? {
+            let file_type = entry.file_type().await?;
+            let path = entry.path();
+            if file_type.is_dir() {
+                dirs.push(path);
+                continue;
+            }
+            if !file_type.is_file() {
+                continue;
+            }
+
+            // The manifest uses `/` separated paths, whatever the platform.
+            let relative = path
+                .strip_prefix(root)
+                .unwrap_or(&path)
+                .components()
+                .map(|c| c.as_os_str().to_str())
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("Invalid file name: {}", path.display()),
+                    )
+                })?
+                .join("/");
+            let metadata = entry.metadata().await?;
+            let sha256 = hash_file(&path).await?;
+            files.push((
+                path,
+                ManifestEntry {
+                    path: relative,
+                    size: metadata.len(),
+                    sha256,
+                    mode: file_mode(&metadata),
+                },
+            ));
+        }
+    }
+
+    files.sort_by(|(_, a), (_, b)| a.path.cmp(&b.path));
+    Ok(files)
+}
+
+#[cfg(unix)]
+fn file_mode(metadata: &std::fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+
+    Some(metadata.permissions().mode() & 0o777)
+}
+
+#[cfg(not(unix))]
+fn file_mode(_metadata: &std::fs::Metadata) -> Option<u32> {
+    None
+}
+
+/// Asks whatever listens at the given address who it is, returning its device if it is a peer.
+pub async fn probe<H: Host>(host: &H, address: &str) -> Result<serde_json::Value, String> {
+    let address = with_default_port(address);
+    let identity = tokio::time::timeout(PROBE_TIMEOUT, async {
+        let (mut ws_sender, mut ws_receiver, _) = connect_secure(host, &address).await?;
+        ws_sender
+            .send(P2pMessage::Probe.into())
+            .await
+            .map_err(|e| e.to_string())?;
+        let identity = next_message(&mut ws_receiver).await;
+        let _ = ws_sender.close().await;
+        identity
+    })
+    .await
+    .unwrap_or(Err("Timed out.".to_string()))?;
+
+    let P2pMessage::Identity {
+        device_id,
+        device_name,
+        version,
+    } = identity
+    else {
+        return Err("The peer did not say who it is.".to_string());
+    };
+    let paired = host.devices().secret(&device_id).is_some();
+
+    Ok(json!({
+        "address": address,
+        "device_id": device_id,
+        "device_name": device_name,
+        "version": version,
+        "compatible": version == PROTOCOL_VERSION,
+        "paired": paired
+    }))
+}
+
+/// Pairs with the peer at the given address, returning the peer's device.
+///
+/// Both sides show the same code, and the users of both accept the pairing once they match. With
+/// the token from the peer's connection card, the peer knows it was scanned from its card.
+pub async fn pair<H: Host>(
+    host: &H,
+    address: &str,
+    token: Option<String>,
+) -> Result<serde_json::Value, PeerError> {
+    let address = with_default_port(address);
+    let devices = host.devices();
+    let (device_id, device_name) = devices.identity();
+
+    // The peer answers with its challenge before asking its user, so that part of pairing is bounded
+    // like any handshake.
+    let nonce = random_hex(16);
+    let requested = async {
+        let (mut ws_sender, mut ws_receiver, session) = connect_secure(host, &address).await?;
+        ws_sender
+            .send(
+                P2pMessage::PairRequest {
+                    version: PROTOCOL_VERSION,
+                    device_id,
+                    device_name,
+                    nonce: nonce.clone(),
+                    token,
+                }
+                .into(),
+            )
+            .await
+            .map_err(|e| e.to_string())?;
+        let challenge = next_message(&mut ws_receiver).await?;
+        Ok::<_, PeerError>((ws_sender, ws_receiver, session, challenge))
+    };
+    let (mut ws_sender, mut ws_receiver, session, challenge) =
+        tokio::time::timeout(HANDSHAKE_TIMEOUT, requested)
+            .await
+            .unwrap_or(Err(PeerError::TimedOut(PAIR_TIMED_OUT.to_string())))?;
+
+    // Show the code so the user can confirm it matches the one the peer shows.
+    let (peer_id, peer_name, peer_nonce) = match challenge {
+        P2pMessage::PairChallenge {
+            device_id,
+            device_name,
+            nonce,
+        } => (device_id, device_name, nonce),
+        _ => return Err("The peer did not answer the pairing request.".into()),
+    };
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let prompt = host.ask(
+        "pair_code",
+        json!({
+            "code": pairing_code(&session.session_id, &nonce, &peer_nonce),
+            "device_name": peer_name,
+            "peer": address
+        }),
+        sender,
+    );
+
+    // Only trust the peer once both users accepted, so a peer at a spoofed address is not trusted
+    // without anyone comparing the codes here.
+    let confirmed = async {
+        match tokio::time::timeout(ASK_TIMEOUT, receiver.recv()).await {
+            Ok(Some(true)) => Ok(()),
+            _ => Err(PeerError::Declined(
+                "The pairing was not confirmed.".to_string(),
+            )),
+        }
+    };
+    let answered = async {
+        match tokio::time::timeout(ASK_TIMEOUT, next_message(&mut ws_receiver)).await {
+            Ok(message) => message.map_err(PeerError::Failed),
+            Err(_) => Err(PeerError::TimedOut(PAIR_TIMED_OUT.to_string())),
+        }
+    };
+    let result = futures::future::try_join(confirmed, answered).await;
+    drop(prompt);
+    let _ = ws_sender.close().await;
+    match result? {
+        (
+            (),
+            P2pMessage::PairResult {
+                accepted: true,
+                secret,
+            },
+        ) if !secret.is_empty() => {
+            devices.trust(
+                peer_id.clone(),
+                peer_name.clone(),
+                secret,
+                session.remote_key,
+            );
+            devices.set_address(&peer_id, address.clone());
+            host.report(
+                "paired",
+                json!({"device_id": peer_id, "device_name": peer_name, "peer": address}),
+            );
+            Ok(json!({"device_id": peer_id, "device_name": peer_name}))
+        }
+        _ => Err(PeerError::Declined(
+            "The peer declined pairing.".to_string(),
+        )),
+    }
+}
+
+/// Connects and encrypts the connection, returning the session to check the peer's key against.
+async fn connect_secure<H: Host>(
+    host: &H,
+    address: &str,
+) -> Result<(SecureSender, SecureReceiver, Session), String> {
+    let ws_stream = connect_ws(address).await?;
+    host.log(format!("Connected to peer: {}", address));
+    let (ws_sender, ws_receiver) = ws_stream.split();
+
+    let private_key = host.devices().private_key();
+    secure::initiate(&private_key, ws_sender, ws_receiver).await
+}
+
+/// Connects and runs the handshake (proving we are paired), returning what both peers agreed on.
+async fn connect<H: Host>(
+    host: &H,
+    address: &str,
+) -> Result<(SecureSender, SecureReceiver, Negotiated), PeerError> {
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake(host, address))
+        .await
+        .unwrap_or(Err(PeerError::TimedOut(
+            "Timed out connecting to the peer.".to_string(),
+        )))
+}
+
+/// Runs the handshake for `connect`, which bounds how long it may take.
+async fn handshake<H: Host>(
+    host: &H,
+    address: &str,
+) -> Result<(SecureSender, SecureReceiver, Negotiated), PeerError> {
+    let (mut ws_sender, mut ws_receiver, session) = connect_secure(host, address).await?;
+
+    let devices = host.devices();
+    let (device_id, device_name) = devices.identity();
+    ws_sender
+        .send(P2pMessage::hello(device_id, device_name, host.listen_port()).into())
+        .await
+        .map_err(|e| e.to_string())?;
+
+    // Answer the challenge with the secret shared when pairing.
+    let peer_id = match next_reply(&mut ws_receiver).await? {
+        P2pMessage::AuthChallenge {
+            device_id, nonce, ..
+        } => {
+            let Some(secret) = devices.secret(&device_id) else {
+                let _ = ws_sender.close().await;
+                return Err(PeerError::Unpaired(
+                    "This device is not paired with the peer.".to_string(),
+                ));
+            };
+            if !devices.check_key(&device_id, &session.remote_key) {
+                let _ = ws_sender.close().await;
+                return Err(PeerError::Unpaired(
+                    "The peer's key does not match the one it paired with.".to_string(),
+                ));
+            }
+            ws_sender
+                .send(
+                    P2pMessage::AuthResponse {
+                        proof: auth_proof(&secret, &nonce),
+                    }
+                    .into(),
+                )
+                .await
+                .map_err(|e| e.to_string())?;
+            device_id
+        }
+        _ => return Err("The peer did not complete the handshake.".into()),
+    };
+
+    match next_reply(&mut ws_receiver).await? {
+        P2pMessage::Welcome {
+            capabilities,
+            compression,
+            ..
+        } => {
+            devices.set_address(&peer_id, address.to_string());
+            let negotiated = Negotiated {
+                capabilities,
+                compression: compression.filter(|c| COMPRESSIONS.contains(c)),
+            };
+            Ok((ws_sender, ws_receiver, negotiated))
+        }
+        _ => Err("The peer did not complete the handshake.".into()),
+    }
+}
+
+/// Waits for the peer's next handshake message, telling a peer that doesn't know us apart.
+async fn next_reply(ws_receiver: &mut SecureReceiver) -> Result<P2pMessage, PeerError> {
+    match read_message(ws_receiver).await? {
+        P2pMessage::Error {
+            code: ErrorCode::Unpaired | ErrorCode::AuthFailed,
+            message,
+        } => Err(PeerError::Unpaired(message)),
+        P2pMessage::Error { message, .. } => Err(PeerError::Failed(message)),
+        reply => Ok(reply),
+    }
+}
@@ -0,0 +1,286 @@
+use super::client::TextConnection;
+use super::devices::Devices;
+use super::peers::Peers;
+use arboard::Clipboard;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use tauri::{AppHandle, Manager};
+use tokio::sync::watch;
+
+/// How often the local clipboard is checked for changes.
+const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(500);
+
+/// What became of a clipboard change a peer sent.
+#[derive(Debug, PartialEq)]
+pub enum Received {
+    Copied,
+    /// Sync is off here, or copying failed.
+    Ignored,
+    /// The sender is not a device the user opted in to sync with, so the text is only shown.
+    NotSynced,
+}
+
+/// Sends local clipboard changes to paired devices, and copies the ones they send.
+#[derive(Default)]
+pub struct ClipboardSync {
+    enabled: AtomicBool,
+    /// Whether the watcher thread is running (it stops soon after sync is turned off).
+    watching: AtomicBool,
+    /// The last text seen on (or written to) the clipboard.
+    ///
+    /// Text received from a peer is stored here before the watcher can see it, so it is never sent
+    /// back, which would have two synced devices send the same text back and forth forever.
+    last: Mutex<String>,
+    /// The latest change to send to each device (by id), picked up by its sync task.
+    devices: Mutex<HashMap<String, watch::Sender<String>>>,
+}
+
+impl ClipboardSync {
+    pub fn enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Turns sync on or off. The clipboard is only watched while it is on, starting from what is
+    /// on it now, so turning sync on doesn't send old text.
+    pub fn set_enabled(&self, app: &AppHandle, enabled: bool) {
+        if !enabled {
+            self.enabled.store(false, Ordering::Relaxed);
+            // Dropping the senders ends the sync tasks, closing their connections.
+            self.devices.lock().unwrap().clear();
+            return;
+        }
+
+        let current = Clipboard::new()
+            .and_then(|mut clipboard| clipboard.get_text())
+            .unwrap_or_default();
+        *self.last.lock().unwrap() = current;
+        self.enabled.store(true, Ordering::Relaxed);
+
+        if !self.watching.swap(true, Ordering::Relaxed) {
+            watch_clipboard(app.clone());
+        }
+    }
+
+    /// Copies text received from a peer, if sync is on and the device it was identified as is
+    /// opted in to it.
+    pub fn receive(
+        &self,
+        peers: &Peers,
+        devices: &Devices,
+        peer: SocketAddr,
+        text: &str,
+    ) -> Received {
+        if !self.enabled() {
+            return Received::Ignored;
+        }
+
+        let device_id = peers
+            .list()
+            .into_iter()
+            .find(|p| p.address == peer)
+            .and_then(|p| p.device_id);
+        let synced = device_id.is_some_and(|id| {
+            devices
+                .trusted()
+                .iter()
+                .any(|d| d.device_id == id && d.clipboard_sync)
+        });
+        if !synced {
+            return Received::NotSynced;
+        }
+
+        // Hold the lock while copying so the watcher never sees the text as a local change.
+        let mut last = self.last.lock().unwrap();
+        if *last == text {
+            return Received::Copied;
+        }
+
+        if !crate::copy_text(text.to_string()) {
+            return Received::Ignored;
+        }
+
+        *last = text.to_string();
+        Received::Copied
+    }
+
+    /// Records what is on the clipboard, returning whether it changed since last time.
+    fn changed(&self, text: &str) -> bool {
+        let mut last = self.last.lock().unwrap();
+        if *last == text {
+            return false;
+        }
+
+        *last = text.to_string();
+        true
+    }
+
+    /// Stops sending changes to a device, ending its sync task (and closing its connection).
+    pub fn stop_device(&self, device_id: &str) {
+        self.devices.lock().unwrap().remove(device_id);
+    }
+
+    /// Hands a change to the device's sync task, starting it if needed.
+    fn send(&self, app: &AppHandle, device_id: &str, text: String) {
+        let mut devices = self.devices.lock().unwrap();
+        if let Some(sender) = devices.get(device_id) {
+            if sender.send(text.clone()).is_ok() {
+                return;
+            }
+        }
+
+        let (sender, receiver) = watch::channel(text);
+        devices.insert(device_id.to_string(), sender);
+        let app = app.clone();
+        let device_id = device_id.to_string();
+        tauri::async_runtime::spawn(async move {
+            sync_device(app, device_id, receiver).await;
+        });
+    }
+}
+
+/// Watches the local clipboard on its own thread while sync is enabled, sending changes to the
+/// paired devices opted in to it.
+fn watch_clipboard(app: AppHandle) {
+    std::thread::spawn(move || {
+        let Ok(mut clipboard) = Clipboard::new() else {
+            println!("Clipboard sync unavailable: can't access the clipboard.");
+            app.state::<ClipboardSync>()
+                .watching
+                .store(false, Ordering::Relaxed);
+            return;
+        };
+
+        loop {
+            std::thread::sleep(POLL_INTERVAL);
+
+            let sync = app.state::<ClipboardSync>();
+            if !sync.enabled() {
+                sync.watching.store(false, Ordering::Relaxed);
+                // Sync may have been turned back on before the flag was cleared.
+                if !sync.enabled() || sync.watching.swap(true, Ordering::Relaxed) {
+                    return;
+                }
+            }
+
+            let Ok(text) = clipboard.get_text() else {
+                continue;
+            };
+            if !sync.changed(&text) || text.is_empty() {
+                continue;
+            }
+
+            let devices = app.state::<Devices>().trusted();
+            for device in devices
+                .into_iter()
+                .filter(|d| d.clipboard_sync && d.address.is_some())
+            {
+                sync.send(&app, &device.device_id, text.clone());
+            }
+        }
+    });
+}
+
+/// Sends the clipboard changes for a device until sync is turned off.
+///
+/// The connection is kept open between changes (peers limit how many can be opened per minute),
+/// and only the latest change is sent when several come in while it is busy.
+async fn sync_device(app: AppHandle, device_id: String, mut changes: watch::Receiver<String>) {
+    let mut connection: Option<TextConnection> = None;
+    loop {
+        let text = {
+            let closed = async {
+                match connection.as_mut() {
+                    Some(connection) => connection.closed().await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                changed = changes.changed() => {
+                    if changed.is_err() {
+                        break;
+                    }
+                    changes.borrow_and_update().clone()
+                }
+                _ = closed => {
+                    connection = None;
+                    continue;
+                }
+            }
+        };
+
+        // A connection the peer closed since is opened again, once.
+        for _ in 0..2 {
+            let open = match connection.as_mut() {
+                Some(open) => open,
+                None => {
+                    let Some(address) = address(&app, &device_id) else {
+                        break;
+                    };
+                    match TextConnection::open(&app, &address).await {
+                        Ok(opened) => connection.insert(opened),
+                        Err(e) => {
+                            println!("Failed syncing clipboard to {}: {}", address, e);
+                            break;
+                        }
+                    }
+                }
+            };
+            match open.send(text.clone(), true).await {
+                Ok(()) => break,
+                Err(_) => connection = None,
+            }
+        }
+    }
+
+    if let Some(connection) = connection {
+        connection.close().await;
+    }
+}
+
+/// Returns where a trusted device was last reached.
+fn address(app: &AppHandle, device_id: &str) -> Option<String> {
+    app.state::<Devices>()
+        .trusted()
+        .into_iter()
+        .find(|d| d.device_id == device_id)
+        .and_then(|d| d.address)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_copies_from_devices_opted_in() {
+        let temp = tempfile::tempdir().unwrap();
+        let devices = Devices::load(temp.path());
+        devices.trust("a".into(), "A".into(), "secret".into(), "key".into());
+        let peers = Peers::default();
+        let peer: SocketAddr = "192.168.1.2:50000".parse().unwrap();
+        let _commands = peers.add(peer);
+        peers.identify(&peer, "a".into(), "A".into());
+
+        let sync = ClipboardSync::default();
+        assert_eq!(
+            sync.receive(&peers, &devices, peer, "text"),
+            Received::Ignored
+        );
+
+        sync.enabled.store(true, Ordering::Relaxed);
+        assert_eq!(
+            sync.receive(&peers, &devices, peer, "text"),
+            Received::NotSynced
+        );
+        assert_eq!(*sync.last.lock().unwrap(), "");
+
+        // Nor from a peer that hasn't said which device it is.
+        let unknown: SocketAddr = "192.168.1.3:50000".parse().unwrap();
+        devices.set_clipboard_sync("a", true);
+        assert_eq!(
+            sync.receive(&peers, &devices, unknown, "text"),
+            Received::NotSynced
+        );
+    }
+}
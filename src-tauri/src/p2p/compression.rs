@@ -0,0 +1,130 @@
+use super::protocol::Compression;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use std::io::{Read, Write};
+
+/// The compressions this build supports, in order of preference.
+pub const COMPRESSIONS: &[Compression] = &[Compression::Deflate];
+
+/// The most a single binary frame can decompress to, so a peer can't send a decompression bomb.
+pub const MAX_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Texts shorter than this are sent as is, compressing them isn't worth it.
+const MIN_TEXT_SIZE: usize = 1024;
+
+/// The most a compressed text can decompress to.
+const MAX_TEXT_SIZE: usize = 16 * 1024 * 1024;
+
+/// The first byte of a binary frame when the transfer is compressed.
+const RAW_CHUNK: u8 = 0;
+const DEFLATE_CHUNK: u8 = 1;
+
+/// Extensions of formats that are already compressed, which are sent without compression.
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "7z", "aac", "avi", "br", "bz2", "docx", "epub", "flac", "gif", "gz", "heic", "jar", "jpeg",
+    "jpg", "lz4", "m4a", "mkv", "mov", "mp3", "mp4", "ogg", "opus", "pdf", "png", "pptx", "rar",
+    "tgz", "webm", "webp", "xlsx", "xz", "zip", "zst",
+];
+
+/// Picks the compression to use with a peer, from the ones it supports.
+pub fn negotiate(peer_compressions: &[Compression]) -> Option<Compression> {
+    COMPRESSIONS
+        .iter()
+        .find(|c| peer_compressions.contains(c))
+        .copied()
+}
+
+/// Whether a file is worth compressing, judging by its extension.
+pub fn is_compressible(file_name: &str) -> bool {
+    let extension = match file_name.rsplit_once('.') {
+        Some((_, extension)) => extension.to_lowercase(),
+        None => return true,
+    };
+
+    !COMPRESSED_EXTENSIONS.contains(&extension.as_str())
+}
+
+/// Returns how many times smaller the data was on the wire (1 when nothing was sent yet).
+pub fn ratio(data_bytes: u64, wire_bytes: u64) -> f64 {
+    if wire_bytes == 0 {
+        return 1.0;
+    }
+
+    data_bytes as f64 / wire_bytes as f64
+}
+
+/// Encodes file data into a binary frame, only compressing it when that makes it smaller.
+pub fn encode_chunk(compression: Option<Compression>, data: &[u8]) -> Vec<u8> {
+    let Some(Compression::Deflate) = compression else {
+        return data.to_vec();
+    };
+
+    let mut frame = vec![DEFLATE_CHUNK];
+    let mut encoder = DeflateEncoder::new(&mut frame, flate2::Compression::fast());
+    let compressed = encoder.write_all(data).and_then(|_| encoder.finish());
+    if compressed.is_ok() && frame.len() <= data.len() {
+        return frame;
+    }
+
+    let mut frame = Vec::with_capacity(data.len() + 1);
+    frame.push(RAW_CHUNK);
+    frame.extend_from_slice(data);
+    frame
+}
+
+/// Decodes a binary frame back into the file data it holds.
+pub fn decode_chunk(compression: Option<Compression>, frame: Vec<u8>) -> std::io::Result<Vec<u8>> {
+    if compression.is_none() {
+        return Ok(frame);
+    }
+
+    match frame.split_first() {
+        Some((&RAW_CHUNK, data)) => Ok(data.to_vec()),
+        Some((&DEFLATE_CHUNK, data)) => inflate(data, MAX_CHUNK_SIZE),
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Invalid compressed chunk.",
+        )),
+    }
+}
+
+/// Compresses a text to send in a text message (base64 encoded), or returns `None` when it's not
+/// worth it.
+pub fn compress_text(compression: Option<Compression>, text: &str) -> Option<String> {
+    if compression != Some(Compression::Deflate) || text.len() < MIN_TEXT_SIZE {
+        return None;
+    }
+
+    let mut encoder = DeflateEncoder::new(vec![], flate2::Compression::default());
+    encoder.write_all(text.as_bytes()).ok()?;
+    let encoded = STANDARD.encode(encoder.finish().ok()?);
+
+    (encoded.len() < text.len()).then_some(encoded)
+}
+
+/// Decompresses a text sent by `compress_text`.
+pub fn decompress_text(text: &str) -> std::io::Result<String> {
+    let data = STANDARD
+        .decode(text)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+
+    String::from_utf8(inflate(&data, MAX_TEXT_SIZE)?)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+/// Decompresses data, failing if it decompresses to more than the limit.
+fn inflate(data: &[u8], limit: usize) -> std::io::Result<Vec<u8>> {
+    let mut inflated = vec![];
+    DeflateDecoder::new(data)
+        .take(limit as u64 + 1)
+        .read_to_end(&mut inflated)?;
+    if inflated.len() > limit {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Compressed data is too large.",
+        ));
+    }
+
+    Ok(inflated)
+}
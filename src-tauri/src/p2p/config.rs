@@ -0,0 +1,176 @@
+use super::access::Networks;
+use super::receive::ConflictPolicy;
+use super::transfers::PartialPolicy;
+use super::P2P_PORT;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+/// The app's config file (in the app config dir), written by the frontend.
+const CONFIG_FILE: &str = "config.json";
+
+/// The P2P settings, from the `p2p` section of the config file.
+///
+/// The listen settings only apply when the server starts (so after a restart), the others apply
+/// right away.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct P2pConfig {
+    /// The most KiB per second sent to all peers together (0 for no limit).
+    pub global_rate_limit: u64,
+    /// The most KiB per second sent by each transfer (0 for no limit).
+    pub transfer_rate_limit: u64,
+    /// The address the server listens on (all interfaces by default). Either unspecified address
+    /// (`0.0.0.0` or `::`) listens on all interfaces, on both IPv4 and IPv6.
+    pub listen_address: String,
+    pub listen_port: u16,
+    /// Only listen on this network interface (by name), instead of `listen_address`.
+    pub listen_interface: Option<String>,
+    /// Listen on a random port when the configured one is taken.
+    pub random_port_fallback: bool,
+    /// Announce this device on the local network (with mDNS) and look for others.
+    pub discovery: bool,
+    /// Serve the web page (to upload files, and download the shared ones by their link) to
+    /// browsers, which unlike peers are neither paired nor encrypted.
+    pub web_page: bool,
+    /// Only ask about pair requests made with a token from our connection card, declining the
+    /// others right away.
+    pub card_pairing_only: bool,
+    /// How often (in seconds) connected peers are pinged.
+    pub ping_interval: u64,
+    /// How many pings in a row a peer can leave unanswered before it is disconnected.
+    pub max_missed_pongs: u32,
+    /// How long (in seconds) a connection can go without any activity before it is closed (0 to
+    /// keep it open). Connections with a running (or paused) transfer are never idle.
+    pub idle_timeout: u64,
+    /// Only accept connections from these networks (in CIDR notation, or single addresses), or
+    /// from anywhere when empty.
+    pub allowed_networks: Vec<String>,
+    /// Never accept connections from these networks, even if allowed.
+    pub denied_networks: Vec<String>,
+    /// Both lists of networks, parsed when the config is read.
+    #[serde(skip)]
+    pub networks: Networks,
+    /// How many connections a single address can open per minute (0 for no limit).
+    pub max_connections_per_minute: u32,
+    /// How many connections can be open at once (0 for no limit).
+    pub max_connections: u32,
+    /// The folder received files are saved to (the downloads folder when not set).
+    pub receive_dir: Option<String>,
+    /// What to do with a received file whose name is taken.
+    pub name_conflict: ConflictPolicy,
+    /// The biggest file (or folder) accepted, in MiB (0 for no limit).
+    pub max_receive_size: u64,
+    /// What happens to the partially received file when a transfer is cancelled.
+    pub partial_policy: PartialPolicy,
+    /// The paired device (by id) the send selection shortcut sends to, the user picks one when
+    /// not set.
+    pub default_peer: Option<String>,
+}
+
+impl Default for P2pConfig {
+    fn default() -> Self {
+        Self {
+            global_rate_limit: 0,
+            transfer_rate_limit: 0,
+            listen_address: "0.0.0.0".to_string(),
+            listen_port: P2P_PORT,
+            listen_interface: None,
+            random_port_fallback: true,
+            discovery: true,
+            web_page: false,
+            card_pairing_only: false,
+            ping_interval: 15,
+            max_missed_pongs: 3,
+            idle_timeout: 300,
+            allowed_networks: Vec::new(),
+            denied_networks: Vec::new(),
+            networks: Networks::default(),
+            max_connections_per_minute: 30,
+            max_connections: 32,
+            receive_dir: None,
+            name_conflict: ConflictPolicy::Rename,
+            max_receive_size: 0,
+            partial_policy: PartialPolicy::Delete,
+            default_peer: None,
+        }
+    }
+}
+
+/// The current P2P settings, reloaded whenever the frontend saves the config.
+pub struct Config {
+    path: PathBuf,
+    config: RwLock<P2pConfig>,
+}
+
+impl Config {
+    /// Loads the config file from the given directory, using the defaults for whatever can't be
+    /// read.
+    pub fn load(dir: &Path) -> Self {
+        let path = dir.join(CONFIG_FILE);
+        let (config, errors) = read(&path);
+        for error in errors {
+            println!("Config: {}", error);
+        }
+        Self {
+            path,
+            config: RwLock::new(config),
+        }
+    }
+
+    pub fn get(&self) -> P2pConfig {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Reads the config file again, returning the new settings and the problems found.
+    pub fn reload(&self) -> (P2pConfig, Vec<String>) {
+        let (config, errors) = read(&self.path);
+        *self.config.write().unwrap() = config.clone();
+        (config, errors)
+    }
+}
+
+/// Reads the `p2p` section of the config file, returning the settings and the problems found.
+///
+/// Each setting is read on its own, so one that can't be read keeps its default without the
+/// others being lost.
+fn read(path: &Path) -> (P2pConfig, Vec<String>) {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Default::default(),
+        Err(e) => return (P2pConfig::default(), vec![format!("Can't read it: {}", e)]),
+    };
+    let section = match serde_json::from_str::<serde_json::Value>(&contents) {
+        Ok(serde_json::Value::Object(mut config)) => config.remove("p2p").unwrap_or_default(),
+        Ok(_) => {
+            let error = "The config file is not a JSON object.".to_string();
+            return (P2pConfig::default(), vec![error]);
+        }
+        Err(e) => return (P2pConfig::default(), vec![format!("Invalid JSON: {}", e)]),
+    };
+    let section = match section {
+        serde_json::Value::Object(section) => section,
+        serde_json::Value::Null => return Default::default(),
+        _ => {
+            let error = "The p2p settings are not an object.".to_string();
+            return (P2pConfig::default(), vec![error]);
+        }
+    };
+
+    let mut config = P2pConfig::default();
+    let mut errors = Vec::new();
+    for (key, value) in section {
+        let mut merged = serde_json::to_value(&config).expect("the settings always serialize");
+        merged[&key] = value;
+        match serde_json::from_value(merged) {
+            Ok(merged) => config = merged,
+            Err(e) => errors.push(format!("Invalid {} setting: {}", key, e)),
+        }
+    }
+
+    let (networks, invalid) = Networks::parse(&config);
+    config.networks = networks;
+    errors.extend(invalid);
+
+    (config, errors)
+}
@@ -0,0 +1,325 @@
+use super::secure::generate_keypair;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use subtle::ConstantTimeEq;
+
+/// The file (in the app config dir) holding this device's identity and its trusted devices.
+const DEVICES_FILE: &str = "p2p_devices.json";
+
+/// A device that has been paired with this one.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TrustedDevice {
+    pub device_id: String,
+    pub device_name: String,
+    /// The secret shared during pairing, used to prove who we are on later connections.
+    pub secret: String,
+    /// The device's public key, pinned when pairing and checked on every encrypted connection.
+    pub public_key: String,
+    /// Unix timestamp (in milliseconds) of when the device was paired.
+    pub paired_at: u128,
+    /// Where the device was last reached (`host:port`), used to push clipboard changes to it.
+    #[serde(default)]
+    pub address: Option<String>,
+    /// Whether the user opted in to sending clipboard changes to the device.
+    #[serde(default)]
+    pub clipboard_sync: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DeviceStore {
+    device_id: String,
+    device_name: String,
+    /// The keypair (hex encoded) used to encrypt connections and identify this device.
+    private_key: String,
+    public_key: String,
+    #[serde(default)]
+    trusted: Vec<TrustedDevice>,
+}
+
+/// This device's identity and the list of devices it trusts, persisted to disk.
+pub struct Devices {
+    path: PathBuf,
+    store: Mutex<DeviceStore>,
+}
+
+impl Devices {
+    /// Loads the devices file from the given directory, creating a new identity if there is none.
+    ///
+    /// A file that can't be read is moved aside rather than overwritten, so the pairings it holds
+    /// can still be recovered by hand.
+    pub fn load(dir: &Path) -> Self {
+        let path = dir.join(DEVICES_FILE);
+        let read = std::fs::read_to_string(&path).and_then(|contents| {
+            serde_json::from_str(&contents)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+        });
+        // Without a backup, the file is left alone (until the next change is saved).
+        let (store, save) = match read {
+            Ok(store) => (Some(store), true),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (None, true),
+            Err(e) => (None, move_aside(&path, &e)),
+        };
+        let store = store.unwrap_or_else(|| {
+            let (private_key, public_key) = generate_keypair();
+            DeviceStore {
+                device_id: random_hex(16),
+                device_name: default_device_name(),
+                private_key,
+                public_key,
+                trusted: vec![],
+            }
+        });
+
+        let devices = Self {
+            path,
+            store: Mutex::new(store),
+        };
+        if save {
+            devices.save(&devices.store.lock().unwrap());
+        }
+
+        devices
+    }
+
+    /// Returns this device's ID and name.
+    pub fn identity(&self) -> (String, String) {
+        let store = self.store.lock().unwrap();
+        (store.device_id.clone(), store.device_name.clone())
+    }
+
+    /// Returns this device's private key, used for the encryption handshake.
+    pub fn private_key(&self) -> String {
+        self.store.lock().unwrap().private_key.clone()
+    }
+
+    /// Returns this device's public key, which peers pin when pairing.
+    pub fn public_key(&self) -> String {
+        self.store.lock().unwrap().public_key.clone()
+    }
+
+    /// Returns whether the key matches the one pinned for a trusted device (never when it has
+    /// none).
+    pub fn check_key(&self, device_id: &str, public_key: &str) -> bool {
+        self.store
+            .lock()
+            .unwrap()
+            .trusted
+            .iter()
+            .find(|d| d.device_id == device_id)
+            .is_some_and(|d| !d.public_key.is_empty() && d.public_key == public_key)
+    }
+
+    /// Returns the secret shared with a trusted device.
+    pub fn secret(&self, device_id: &str) -> Option<String> {
+        self.store
+            .lock()
+            .unwrap()
+            .trusted
+            .iter()
+            .find(|d| d.device_id == device_id)
+            .map(|d| d.secret.clone())
+    }
+
+    pub fn trusted(&self) -> Vec<TrustedDevice> {
+        self.store.lock().unwrap().trusted.clone()
+    }
+
+    /// Adds (or replaces) a trusted device.
+    pub fn trust(
+        &self,
+        device_id: String,
+        device_name: String,
+        secret: String,
+        public_key: String,
+    ) {
+        let mut store = self.store.lock().unwrap();
+        store.trusted.retain(|d| d.device_id != device_id);
+        store.trusted.push(TrustedDevice {
+            device_id,
+            device_name,
+            secret,
+            public_key,
+            paired_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis())
+                .unwrap_or_default(),
+            address: None,
+            clipboard_sync: false,
+        });
+        self.save(&store);
+    }
+
+    /// Remembers where a trusted device was reached.
+    pub fn set_address(&self, device_id: &str, address: String) {
+        let mut store = self.store.lock().unwrap();
+        let Some(device) = store.trusted.iter_mut().find(|d| d.device_id == device_id) else {
+            return;
+        };
+
+        if device.address.as_ref() != Some(&address) {
+            device.address = Some(address);
+            self.save(&store);
+        }
+    }
+
+    /// Opts a trusted device in to (or out of) clipboard sync, returning false if it is not
+    /// trusted.
+    pub fn set_clipboard_sync(&self, device_id: &str, enabled: bool) -> bool {
+        let mut store = self.store.lock().unwrap();
+        let Some(device) = store.trusted.iter_mut().find(|d| d.device_id == device_id) else {
+            return false;
+        };
+
+        device.clipboard_sync = enabled;
+        self.save(&store);
+        true
+    }
+
+    /// Removes a trusted device, returning false if it was not trusted.
+    pub fn forget(&self, device_id: &str) -> bool {
+        let mut store = self.store.lock().unwrap();
+        let count = store.trusted.len();
+        store.trusted.retain(|d| d.device_id != device_id);
+        if store.trusted.len() == count {
+            return false;
+        }
+
+        self.save(&store);
+        true
+    }
+
+    /// Writes the store to a temporary file first, so a crash while saving never leaves a
+    /// truncated file behind.
+    fn save(&self, store: &DeviceStore) {
+        if let Some(dir) = self.path.parent() {
+            let _ = std::fs::create_dir_all(dir);
+        }
+
+        let contents = match serde_json::to_string_pretty(store) {
+            Ok(contents) => contents,
+            Err(e) => {
+                println!("Failed serializing devices: {}", e);
+                return;
+            }
+        };
+        let temp_path = self.path.with_extension("json.tmp");
+        let saved = std::fs::write(&temp_path, contents)
+            .and_then(|()| std::fs::rename(&temp_path, &self.path));
+        if let Err(e) = saved {
+            println!("Failed saving devices: {}", e);
+            let _ = std::fs::remove_file(&temp_path);
+        }
+    }
+}
+
+/// Renames a devices file that can't be read to a backup (replacing an older one), so a new one
+/// can take its place. Returns whether it was moved.
+fn move_aside(path: &Path, error: &std::io::Error) -> bool {
+    let backup = path.with_extension("json.bak");
+    match std::fs::rename(path, &backup) {
+        Ok(()) => {
+            println!(
+                "Can't read the devices file ({}), moved it to {}",
+                error,
+                backup.display()
+            );
+            true
+        }
+        Err(e) => {
+            println!("Can't read the devices file ({}) nor move it: {}", error, e);
+            false
+        }
+    }
+}
+
+/// Returns `len` random bytes, hex encoded.
+pub fn random_hex(len: usize) -> String {
+    let mut bytes = vec![0; len];
+    rand::thread_rng().fill(&mut bytes[..]);
+    hex::encode(bytes)
+}
+
+/// Returns the 6 digit code both sides show while pairing.
+///
+/// It is derived from the encrypted session and both nonces, so it only matches on both devices
+/// if nobody is sitting in the middle of the connection.
+pub fn pairing_code(session_id: &str, initiator_nonce: &str, receiver_nonce: &str) -> String {
+    let digest = Sha256::digest(format!(
+        "{}{}{}",
+        session_id, initiator_nonce, receiver_nonce
+    ));
+    let number = u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]);
+    format!("{:06}", number % 1_000_000)
+}
+
+/// Returns the proof that a peer knows the shared secret, for the given challenge nonce.
+pub fn auth_proof(secret: &str, nonce: &str) -> String {
+    hex::encode(Sha256::digest(format!("{}{}", secret, nonce)))
+}
+
+/// Checks a peer's proof in constant time, so timing doesn't tell how much of it was right.
+pub fn check_proof(secret: &str, nonce: &str, proof: &str) -> bool {
+    auth_proof(secret, nonce)
+        .as_bytes()
+        .ct_eq(proof.as_bytes())
+        .into()
+}
+
+fn default_device_name() -> String {
+    std::env::var("COMPUTERNAME")
+        .or_else(|_| std::env::var("HOSTNAME"))
+        .ok()
+        .or_else(|| {
+            std::fs::read_to_string("/etc/hostname")
+                .ok()
+                .map(|name| name.trim().to_string())
+        })
+        .filter(|name| !name.is_empty())
+        .unwrap_or("Isaac Utils".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_what_it_saved() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let devices = Devices::load(dir);
+        devices.trust("a".into(), "A".into(), "secret".into(), "key".into());
+
+        let loaded = Devices::load(dir);
+        assert_eq!(loaded.identity(), devices.identity());
+        assert_eq!(loaded.secret("a").as_deref(), Some("secret"));
+        assert!(!dir.join("p2p_devices.json.tmp").exists());
+    }
+
+    #[test]
+    fn moves_an_unreadable_file_aside() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        std::fs::write(dir.join(DEVICES_FILE), "{ not json").unwrap();
+
+        let devices = Devices::load(dir);
+        assert!(devices.trusted().is_empty());
+        assert_eq!(
+            std::fs::read_to_string(dir.join("p2p_devices.json.bak")).unwrap(),
+            "{ not json"
+        );
+        assert_eq!(Devices::load(dir).identity(), devices.identity());
+    }
+
+    #[test]
+    fn checks_the_proof() {
+        let proof = auth_proof("secret", "nonce");
+        assert!(check_proof("secret", "nonce", &proof));
+        assert!(!check_proof("other", "nonce", &proof));
+        assert!(!check_proof("secret", "nonce", &proof[1..]));
+        assert!(!check_proof("secret", "nonce", ""));
+    }
+}
@@ -0,0 +1,214 @@
+#[cfg(feature = "gui")]
+use super::config::Config;
+use super::devices::Devices;
+#[cfg(feature = "gui")]
+use super::emit_p2p;
+use super::protocol::{PROTOCOL_VERSION, SERVICE_TYPE};
+#[cfg(feature = "gui")]
+use mdns_sd::ServiceEvent;
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+use serde::Serialize;
+#[cfg(feature = "gui")]
+use serde_json::json;
+#[cfg(feature = "gui")]
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+#[cfg(feature = "gui")]
+use std::sync::Mutex;
+#[cfg(feature = "gui")]
+use tauri::{AppHandle, Manager};
+
+/// Another instance found on the local network.
+#[derive(Serialize, Clone)]
+pub struct DiscoveredPeer {
+    pub device_id: String,
+    pub device_name: String,
+    pub addresses: Vec<IpAddr>,
+    pub port: u16,
+    pub version: u32,
+    /// Whether it is one of our trusted devices.
+    pub paired: bool,
+}
+
+/// The instances currently announced on the local network, kept up to date by the mDNS browser.
+#[cfg(feature = "gui")]
+#[derive(Default)]
+pub struct Discovery {
+    daemon: Mutex<Option<ServiceDaemon>>,
+    peers: Mutex<HashMap<String, DiscoveredPeer>>,
+}
+
+#[cfg(feature = "gui")]
+impl Discovery {
+    /// Returns the mDNS daemon, starting it the first time.
+    fn daemon(&self) -> Option<ServiceDaemon> {
+        let mut daemon = self.daemon.lock().unwrap();
+        if daemon.is_none() {
+            match ServiceDaemon::new() {
+                Ok(started) => *daemon = Some(started),
+                Err(e) => println!("Discovery unavailable: {}", e),
+            }
+        }
+
+        daemon.clone()
+    }
+
+    /// Returns the discovered peers, marking which ones are paired.
+    pub fn list(&self, devices: &Devices) -> Vec<DiscoveredPeer> {
+        let trusted = devices.trusted();
+        let mut peers: Vec<_> = self.peers.lock().unwrap().values().cloned().collect();
+        for peer in peers.iter_mut() {
+            peer.paired = trusted.iter().any(|d| d.device_id == peer.device_id);
+        }
+        peers.sort_by(|a, b| a.device_name.cmp(&b.device_name));
+
+        peers
+    }
+
+    /// Returns where a discovered peer can be reached, skipping link-local IPv6 addresses (which
+    /// need the scope of an interface).
+    pub fn address(&self, device_id: &str) -> Option<SocketAddr> {
+        let peers = self.peers.lock().unwrap();
+        let peer = peers.values().find(|p| p.device_id == device_id)?;
+        peer.addresses
+            .iter()
+            .find(|ip| !matches!(ip, IpAddr::V6(ip) if ip.is_unicast_link_local()))
+            .map(|ip| SocketAddr::new(*ip, peer.port))
+    }
+}
+
+/// Announces this device on the local network, so others can find the server at the given address.
+#[cfg(feature = "gui")]
+pub fn announce(app: &AppHandle, address: SocketAddr) {
+    if !app.state::<Config>().get().discovery {
+        return;
+    }
+    let Some(daemon) = app.state::<Discovery>().daemon() else {
+        return;
+    };
+
+    if let Err(e) = register(&daemon, &app.state::<Devices>(), address) {
+        println!("Failed announcing this device: {}", e);
+    }
+}
+
+/// Registers this device with the mDNS daemon, as the server listening at the given address.
+pub fn register(
+    daemon: &ServiceDaemon,
+    devices: &Devices,
+    address: SocketAddr,
+) -> mdns_sd::Result<()> {
+    let (device_id, device_name) = devices.identity();
+    let properties = [
+        ("id", device_id.clone()),
+        ("name", device_name),
+        ("version", PROTOCOL_VERSION.to_string()),
+    ];
+    let host_name = format!("{}.local.", device_id);
+
+    // Listening on all interfaces announces all of their addresses.
+    let service = if address.ip().is_unspecified() {
+        ServiceInfo::new(
+            SERVICE_TYPE,
+            &device_id,
+            &host_name,
+            "",
+            address.port(),
+            &properties[..],
+        )
+        .map(|service| service.enable_addr_auto())
+    } else {
+        ServiceInfo::new(
+            SERVICE_TYPE,
+            &device_id,
+            &host_name,
+            address.ip(),
+            address.port(),
+            &properties[..],
+        )
+    };
+
+    service.and_then(|service| daemon.register(service))
+}
+
+/// Watches for other instances on its own thread, letting the UI know as they come and go.
+#[cfg(feature = "gui")]
+pub fn browse(app: AppHandle) {
+    if !app.state::<Config>().get().discovery {
+        return;
+    }
+    let Some(daemon) = app.state::<Discovery>().daemon() else {
+        return;
+    };
+    let receiver = match daemon.browse(SERVICE_TYPE) {
+        Ok(receiver) => receiver,
+        Err(e) => {
+            println!("Failed browsing for devices: {}", e);
+            return;
+        }
+    };
+
+    std::thread::spawn(move || {
+        let (own_id, _) = app.state::<Devices>().identity();
+
+        while let Ok(event) = receiver.recv() {
+            match event {
+                ServiceEvent::ServiceResolved(service) => {
+                    let Some(peer) = parse(&service).filter(|p| p.device_id != own_id) else {
+                        continue;
+                    };
+
+                    let discovery = app.state::<Discovery>();
+                    discovery
+                        .peers
+                        .lock()
+                        .unwrap()
+                        .insert(service.get_fullname().to_string(), peer.clone());
+                    let paired = app
+                        .state::<Devices>()
+                        .trusted()
+                        .iter()
+                        .any(|d| d.device_id == peer.device_id);
+                    emit_p2p(
+                        &app,
+                        "peer_discovered",
+                        json!(DiscoveredPeer { paired, ..peer }),
+                    );
+                }
+                ServiceEvent::ServiceRemoved(_, fullname) => {
+                    let removed = app
+                        .state::<Discovery>()
+                        .peers
+                        .lock()
+                        .unwrap()
+                        .remove(&fullname);
+                    if let Some(peer) = removed {
+                        emit_p2p(&app, "peer_lost", json!({ "device_id": peer.device_id }));
+                    }
+                }
+                _ => (),
+            }
+        }
+    });
+}
+
+/// Reads an announced instance, returning `None` if it is not one of ours.
+#[cfg(feature = "gui")]
+fn parse(service: &ServiceInfo) -> Option<DiscoveredPeer> {
+    // The easiest to reach first: IPv4, then global IPv6, then link-local IPv6 (which needs the
+    // interface it is on to connect).
+    let mut addresses: Vec<_> = service.get_addresses().iter().copied().collect();
+    addresses.sort_by_key(|ip| {
+        let link_local = matches!(ip, IpAddr::V6(v6) if v6.is_unicast_link_local());
+        (ip.is_ipv6(), link_local, *ip)
+    });
+
+    Some(DiscoveredPeer {
+        device_id: service.get_property_val_str("id")?.to_string(),
+        device_name: service.get_property_val_str("name")?.to_string(),
+        addresses,
+        port: service.get_port(),
+        version: service.get_property_val_str("version")?.parse().ok()?,
+        paired: false,
+    })
+}
@@ -0,0 +1,440 @@
+#[cfg(feature = "gui")]
+use super::devices::Devices;
+#[cfg(feature = "gui")]
+use super::emit_p2p;
+#[cfg(feature = "gui")]
+use super::peers::Peers;
+use super::transfers::Direction;
+#[cfg(feature = "gui")]
+use super::transfers::TransferInfo;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::io::Write;
+#[cfg(feature = "gui")]
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+#[cfg(feature = "gui")]
+use std::time::{SystemTime, UNIX_EPOCH};
+#[cfg(feature = "gui")]
+use tauri::{AppHandle, Manager};
+
+/// The file (in the app data dir) the transfer history is appended to, one JSON entry per line.
+const HISTORY_FILE: &str = "p2p_history.jsonl";
+
+/// How a transfer ended.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+/// A finished transfer, as kept in the history.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct HistoryEntry {
+    pub id: String,
+    pub direction: Direction,
+    /// The peer's address (`host:port`), and the paired device's name when known.
+    pub peer: String,
+    pub peer_name: Option<String>,
+    /// The file (or folder) name.
+    pub name: String,
+    pub size: u64,
+    pub sha256: Option<String>,
+    pub outcome: Outcome,
+    pub error: Option<String>,
+    /// Unix timestamp (in milliseconds) of when the transfer started.
+    pub started_at: u128,
+    /// How long the transfer took, in milliseconds.
+    pub duration: u128,
+    /// How many times smaller the data was on the wire, when compressed.
+    #[serde(default)]
+    pub compression_ratio: Option<f64>,
+}
+
+/// Which history entries to return (all of them by default), newest first.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct HistoryQuery {
+    /// Part of the peer's address or name.
+    pub peer: Option<String>,
+    pub direction: Option<Direction>,
+    pub outcome: Option<Outcome>,
+    /// Part of the file name (case insensitive).
+    pub search: Option<String>,
+    /// Only transfers started in this range (unix timestamps in milliseconds).
+    pub since: Option<u128>,
+    pub until: Option<u128>,
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
+
+impl HistoryQuery {
+    fn matches(&self, entry: &HistoryEntry) -> bool {
+        let contains =
+            |value: &str, part: &str| value.to_lowercase().contains(&part.to_lowercase());
+
+        self.peer.as_ref().is_none_or(|peer| {
+            contains(&entry.peer, peer)
+                || entry
+                    .peer_name
+                    .as_ref()
+                    .is_some_and(|name| contains(name, peer))
+        }) && self.direction.is_none_or(|d| d == entry.direction)
+            && self.outcome.is_none_or(|o| o == entry.outcome)
+            && self
+                .search
+                .as_ref()
+                .is_none_or(|s| contains(&entry.name, s))
+            && self.since.is_none_or(|since| entry.started_at >= since)
+            && self.until.is_none_or(|until| entry.started_at <= until)
+    }
+}
+
+/// The log of finished transfers, persisted to disk.
+pub struct History {
+    path: PathBuf,
+    entries: Mutex<Vec<HistoryEntry>>,
+}
+
+impl History {
+    /// Loads the history file from the given directory, skipping lines that can't be read.
+    pub fn load(dir: &Path) -> Self {
+        let entries = std::fs::read_to_string(dir.join(HISTORY_FILE))
+            .map(|contents| {
+                contents
+                    .lines()
+                    .filter_map(|line| serde_json::from_str(line).ok())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Self {
+            path: dir.join(HISTORY_FILE),
+            entries: Mutex::new(entries),
+        }
+    }
+
+    /// Adds an entry, appending it to the history file.
+    pub fn add(&self, entry: HistoryEntry) {
+        let mut entries = self.entries.lock().unwrap();
+        if let Err(e) = self.append(&entry) {
+            println!("Failed saving transfer history: {}", e);
+        }
+        entries.push(entry);
+    }
+
+    pub fn query(&self, query: &HistoryQuery) -> Vec<HistoryEntry> {
+        let entries = self.entries.lock().unwrap();
+        let matching = entries
+            .iter()
+            .rev()
+            .filter(|e| query.matches(e))
+            .skip(query.offset);
+
+        match query.limit {
+            Some(limit) => matching.take(limit).cloned().collect(),
+            None => matching.cloned().collect(),
+        }
+    }
+
+    /// Removes every entry, from memory and disk.
+    pub fn clear(&self) -> std::io::Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.clear();
+        match std::fs::remove_file(&self.path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Writes the whole history to a file, as CSV if its extension is `csv` and JSON otherwise.
+    pub fn export(&self, path: &Path) -> std::io::Result<()> {
+        let entries = self.entries.lock().unwrap();
+        let is_csv = path
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("csv"));
+        let contents = if is_csv {
+            to_csv(&entries)
+        } else {
+            serde_json::to_string_pretty(&*entries)?
+        };
+
+        std::fs::write(path, contents)
+    }
+
+    fn append(&self, entry: &HistoryEntry) -> std::io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{}", serde_json::to_string(entry)?)
+    }
+}
+
+/// Records a finished transfer in the history and lets the UI know.
+#[cfg(feature = "gui")]
+pub fn record(
+    app: &AppHandle,
+    transfer: &TransferInfo,
+    outcome: Outcome,
+    sha256: Option<String>,
+    error: Option<String>,
+) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or_default();
+    let entry = HistoryEntry {
+        id: transfer.id.clone(),
+        direction: transfer.direction,
+        peer: transfer.peer.clone(),
+        peer_name: peer_name(app, &transfer.peer),
+        name: transfer.name.clone(),
+        size: transfer.size,
+        sha256,
+        outcome,
+        error,
+        started_at: transfer.started_at,
+        duration: now.saturating_sub(transfer.started_at),
+        compression_ratio: transfer.compression_ratio,
+    };
+
+    emit_p2p(app, "history_added", json!(entry));
+    app.state::<History>().add(entry);
+}
+
+/// Finds the name of the paired device at an address, either connected to us or last reached there.
+#[cfg(feature = "gui")]
+fn peer_name(app: &AppHandle, peer: &str) -> Option<String> {
+    let connected = peer.parse::<SocketAddr>().ok().and_then(|address| {
+        app.state::<Peers>()
+            .list()
+            .into_iter()
+            .find(|p| p.address == address)?
+            .device_name
+    });
+
+    connected.or_else(|| {
+        app.state::<Devices>()
+            .trusted()
+            .into_iter()
+            .find(|d| d.address.as_deref() == Some(peer))
+            .map(|d| d.device_name)
+    })
+}
+
+fn to_csv(entries: &[HistoryEntry]) -> String {
+    let mut csv =
+        "id,direction,peer,peer_name,name,size,sha256,outcome,error,started_at,duration,compression_ratio\n"
+            .to_string();
+    for entry in entries {
+        let fields = [
+            entry.id.clone(),
+            json!(entry.direction)
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+            entry.peer.clone(),
+            entry.peer_name.clone().unwrap_or_default(),
+            entry.name.clone(),
+            entry.size.to_string(),
+            entry.sha256.clone().unwrap_or_default(),
+            json!(entry.outcome)
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+            entry.error.clone().unwrap_or_default(),
+            entry.started_at.to_string(),
+            entry.duration.to_string(),
+            entry
+                .compression_ratio
+                .map(|ratio| format!("{:.2}", ratio))
+                .unwrap_or_default(),
+        ];
+        let row: Vec<_> = fields.iter().map(|field| csv_field(field)).collect();
+        csv.push_str(&row.join(","));
+        csv.push('\n');
+    }
+
+    csv
+}
+
+/// Quotes a CSV field when it contains separators, quotes or line breaks.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(id: &str, direction: Direction, outcome: Outcome, name: &str) -> HistoryEntry {
+        HistoryEntry {
+            id: id.to_string(),
+            direction,
+            peer: "192.168.1.2:9817".to_string(),
+            peer_name: Some("Laptop".to_string()),
+            name: name.to_string(),
+            size: 10,
+            sha256: None,
+            outcome,
+            error: None,
+            started_at: 1000,
+            duration: 5,
+            compression_ratio: None,
+        }
+    }
+
+    fn ids(history: &History, query: HistoryQuery) -> Vec<String> {
+        history.query(&query).into_iter().map(|e| e.id).collect()
+    }
+
+    fn history() -> (tempfile::TempDir, History) {
+        let temp = tempfile::tempdir().unwrap();
+        let history = History::load(temp.path());
+        history.add(entry(
+            "a",
+            Direction::Send,
+            Outcome::Completed,
+            "Report.pdf",
+        ));
+        history.add(entry("b", Direction::Receive, Outcome::Failed, "photo.jpg"));
+        history.add(HistoryEntry {
+            peer: "10.0.0.5:9817".to_string(),
+            peer_name: None,
+            started_at: 3000,
+            ..entry(
+                "c",
+                Direction::Receive,
+                Outcome::Completed,
+                "report-final.pdf",
+            )
+        });
+        (temp, history)
+    }
+
+    #[test]
+    fn query_returns_everything_newest_first() {
+        let (_temp, history) = history();
+        assert_eq!(ids(&history, HistoryQuery::default()), ["c", "b", "a"]);
+
+        let page = HistoryQuery {
+            offset: 1,
+            limit: Some(1),
+            ..HistoryQuery::default()
+        };
+        assert_eq!(ids(&history, page), ["b"]);
+    }
+
+    #[test]
+    fn query_filters_by_direction_and_outcome() {
+        let (_temp, history) = history();
+        let received = HistoryQuery {
+            direction: Some(Direction::Receive),
+            ..HistoryQuery::default()
+        };
+        assert_eq!(ids(&history, received), ["c", "b"]);
+
+        let received_completed = HistoryQuery {
+            direction: Some(Direction::Receive),
+            outcome: Some(Outcome::Completed),
+            ..HistoryQuery::default()
+        };
+        assert_eq!(ids(&history, received_completed), ["c"]);
+
+        let sent_failed = HistoryQuery {
+            direction: Some(Direction::Send),
+            outcome: Some(Outcome::Failed),
+            ..HistoryQuery::default()
+        };
+        assert!(ids(&history, sent_failed).is_empty());
+    }
+
+    #[test]
+    fn query_matches_part_of_the_peer_address_or_name() {
+        let (_temp, history) = history();
+        let by_name = HistoryQuery {
+            peer: Some("lapt".to_string()),
+            ..HistoryQuery::default()
+        };
+        assert_eq!(ids(&history, by_name), ["b", "a"]);
+
+        let by_address = HistoryQuery {
+            peer: Some("10.0.0".to_string()),
+            ..HistoryQuery::default()
+        };
+        assert_eq!(ids(&history, by_address), ["c"]);
+    }
+
+    #[test]
+    fn query_searches_names_ignoring_case() {
+        let (_temp, history) = history();
+        let search = HistoryQuery {
+            search: Some("REPORT".to_string()),
+            ..HistoryQuery::default()
+        };
+        assert_eq!(ids(&history, search), ["c", "a"]);
+
+        // Every filter given has to match.
+        let combined = HistoryQuery {
+            search: Some("report".to_string()),
+            peer: Some("laptop".to_string()),
+            direction: Some(Direction::Send),
+            ..HistoryQuery::default()
+        };
+        assert_eq!(ids(&history, combined), ["a"]);
+
+        let in_range = HistoryQuery {
+            search: Some("report".to_string()),
+            since: Some(2000),
+            until: Some(3000),
+            ..HistoryQuery::default()
+        };
+        assert_eq!(ids(&history, in_range), ["c"]);
+    }
+
+    #[test]
+    fn to_csv_quotes_fields_that_need_it() {
+        let entries = [
+            HistoryEntry {
+                peer_name: Some("Isaac's \"work\" laptop".to_string()),
+                error: Some("Failed,\nthen gave up".to_string()),
+                compression_ratio: Some(2.5),
+                ..entry("a", Direction::Send, Outcome::Failed, "a, b.txt")
+            },
+            entry("b", Direction::Receive, Outcome::Completed, "plain.txt"),
+        ];
+
+        let csv = to_csv(&entries);
+        let (header, rows) = csv.split_once('\n').unwrap();
+        assert!(header.starts_with("id,direction,peer,peer_name,name,"));
+        assert_eq!(
+            rows,
+            "a,send,192.168.1.2:9817,\"Isaac's \"\"work\"\" laptop\",\"a, b.txt\",10,,failed,\"Failed,\nthen gave up\",1000,5,2.50\n\
+             b,receive,192.168.1.2:9817,Laptop,plain.txt,10,,completed,,1000,5,\n"
+        );
+    }
+
+    #[test]
+    fn history_is_kept_on_disk() {
+        let (temp, history) = history();
+        assert_eq!(
+            ids(&History::load(temp.path()), HistoryQuery::default()),
+            ["c", "b", "a"]
+        );
+
+        history.clear().unwrap();
+        assert!(ids(&History::load(temp.path()), HistoryQuery::default()).is_empty());
+    }
+}
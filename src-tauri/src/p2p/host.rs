@@ -0,0 +1,81 @@
+use super::config::P2pConfig;
+use super::devices::Devices;
+use super::history::Outcome;
+use super::transfers::{Direction, TransferCommand};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// Whatever runs the protocol code: the app, or the headless CLI.
+///
+/// The connection code reports what happens through it and leaves it the decisions it can't make
+/// on its own (asking the user, where files go, how fast to send), so it doesn't depend on Tauri.
+pub trait Host: Send + Sync {
+    /// A question waiting for the user's answer, which stops waiting when dropped.
+    type Prompt: Send;
+    /// A transfer registered while it runs.
+    type Transfer: Transfer + Send;
+
+    fn devices(&self) -> &Devices;
+
+    /// Returns the current settings (read again for each offer, so changes apply right away).
+    fn p2p_config(&self) -> P2pConfig;
+
+    /// Returns the port our own server listens on, for peers to reach it.
+    fn listen_port(&self) -> Option<u16>;
+
+    /// Returns the folder received files are saved to.
+    fn receive_dir(&self) -> std::io::Result<PathBuf>;
+
+    /// Reports something that happened, `data` naming the peer it is about.
+    fn report(&self, event: &str, data: serde_json::Value);
+
+    /// Reports an event the user has to accept (a pairing, file or folder), sending their answer
+    /// through `answers`.
+    fn ask(
+        &self,
+        event: &str,
+        data: serde_json::Value,
+        answers: mpsc::UnboundedSender<bool>,
+    ) -> Self::Prompt;
+
+    /// Logs what is only useful to debug.
+    fn log(&self, message: String);
+
+    /// Registers a transfer, returning it and the receiver to listen to for commands.
+    fn start_transfer(
+        &self,
+        direction: Direction,
+        name: &str,
+        size: u64,
+        peer: &str,
+    ) -> (Self::Transfer, mpsc::UnboundedReceiver<TransferCommand>);
+
+    /// Records that a transfer was paused or resumed (by either side).
+    fn report_paused(&self, id: &str, paused: bool, by_peer: bool);
+
+    /// Records how many times smaller a compressed transfer's data was on the wire so far.
+    fn record_compression_ratio(&self, id: &str, ratio: f64);
+
+    /// Accounts for bytes a transfer is about to send, returning how long to wait before sending
+    /// more (with no bytes, how much of the wait is left).
+    fn throttle(&self, transfer_id: &str, bytes: u64) -> Duration;
+
+    /// Handles a text a peer sent, to show it or (for clipboard sync) to copy it.
+    fn text_received(&self, peer: SocketAddr, text: String, clipboard: bool);
+
+    /// Uses up a pairing token from our connection card, returning whether it was valid.
+    fn redeem_token(&self, token: &str) -> bool;
+
+    /// Records which paired device is on the other end of a connection.
+    fn identify(&self, peer: SocketAddr, device_id: String, device_name: String);
+}
+
+/// A transfer registered with the host while it runs.
+pub trait Transfer {
+    fn id(&self) -> &str;
+
+    /// Records how the transfer ended (it counts as interrupted otherwise).
+    fn finish(&mut self, outcome: Outcome, sha256: Option<String>, error: Option<String>);
+}
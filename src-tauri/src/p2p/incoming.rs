@@ -0,0 +1,1719 @@
+use super::client::hash_file;
+use super::compression;
+use super::devices::{check_proof, pairing_code, random_hex};
+use super::history::Outcome;
+use super::host::{Host, Transfer};
+use super::peers::PeerCommand;
+use super::protocol::{
+    next_message, shared_capabilities, Capability, Compression, ErrorCode, ManifestEntry,
+    Negotiated, P2pMessage, PROTOCOL_VERSION,
+};
+use super::receive::{self, ConflictPolicy};
+use super::secure::{self, Session};
+use super::transfers::{Direction, PartialPolicy, TransferCommand};
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Component, Path, PathBuf};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_tungstenite::{
+    accept_async,
+    tungstenite::{
+        protocol::{frame::coding::CloseCode, CloseFrame},
+        Error, Message, Result,
+    },
+};
+
+/// How long a peer waits for the user to accept or decline a file (or pairing).
+pub const ASK_FILE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// How long a peer has to complete each step of the handshake.
+pub const HANDSHAKE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// The most files a folder offer can list.
+const MAX_FOLDER_ENTRIES: usize = 10_000;
+
+/// How long to wait before accepting connections again after failing to.
+pub const ACCEPT_RETRY_DELAY: std::time::Duration = std::time::Duration::from_millis(100);
+
+/// A file currently being written to disk from a peer.
+///
+/// The data goes to a partial file named after the content hash, so a resumed transfer of the
+/// same content picks up where it left off, and is only moved to its final name once verified.
+struct IncomingFile {
+    path: PathBuf,
+    partial_path: PathBuf,
+    file: File,
+    received: u64,
+    hasher: Sha256,
+}
+
+impl IncomingFile {
+    /// Returns the final and partial paths of a file, refusing names that could end up outside
+    /// the folder (whatever checked them before).
+    fn paths(dir: &Path, file_name: &str, sha256: &str) -> std::io::Result<(PathBuf, PathBuf)> {
+        let relative = safe_relative_path(file_name).filter(|_| is_sha256(sha256));
+        let Some(relative) = relative else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Unsafe file name: {}", file_name),
+            ));
+        };
+        let partial_name = format!("{}.{}.part", file_name, &sha256[..16]);
+
+        Ok((dir.join(relative), dir.join(partial_name)))
+    }
+
+    /// Returns where the mark of a partial file whose transfer the user cancelled goes.
+    fn cancelled_path(partial_path: &Path) -> PathBuf {
+        partial_path.with_extension("part.cancelled")
+    }
+
+    /// Returns whether the user cancelled the transfer that left a partial file of this content.
+    async fn was_cancelled(dir: &Path, file_name: &str, sha256: &str) -> bool {
+        let Ok((_, partial_path)) = Self::paths(dir, file_name, sha256) else {
+            return false;
+        };
+        tokio::fs::try_exists(Self::cancelled_path(&partial_path))
+            .await
+            .unwrap_or(false)
+    }
+
+    /// Returns how many bytes of a previous transfer of the same content are already on disk.
+    async fn existing_len(dir: &Path, file_name: &str, sha256: &str) -> u64 {
+        let Ok((_, partial_path)) = Self::paths(dir, file_name, sha256) else {
+            return 0;
+        };
+        tokio::fs::metadata(partial_path)
+            .await
+            .map(|m| m.len())
+            .unwrap_or(0)
+    }
+
+    async fn open(dir: &Path, file_name: &str, sha256: &str, size: u64) -> std::io::Result<Self> {
+        let (path, partial_path) = Self::paths(dir, file_name, sha256)?;
+
+        // Files in a folder may go in sub folders that don't exist yet.
+        if let Some(parent) = partial_path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&partial_path)
+            .await?;
+        // The user accepted the file again, so a cancelled transfer of it is resumed.
+        let _ = tokio::fs::remove_file(Self::cancelled_path(&partial_path)).await;
+
+        // Hash whatever a previous attempt already wrote.
+        let mut hasher = Sha256::new();
+        let mut received = 0;
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            let read = file.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+
+            hasher.update(&buffer[..read]);
+            received += read as u64;
+        }
+
+        // A partial file bigger than the announced size can't be resumed, so start over.
+        if received > size {
+            file.set_len(0).await?;
+            hasher = Sha256::new();
+            received = 0;
+        }
+
+        Ok(Self {
+            path,
+            partial_path,
+            file,
+            received,
+            hasher,
+        })
+    }
+
+    /// Verifies the content hash and moves the file to its final name.
+    ///
+    /// Another transfer may have taken the name of a single file since it was offered, so its name
+    /// is picked again with the given policy (the files of a folder keep theirs).
+    async fn finish(
+        mut self,
+        sha256: &str,
+        conflict: Option<ConflictPolicy>,
+    ) -> Result<PathBuf, String> {
+        self.file.flush().await.map_err(|e| e.to_string())?;
+        drop(self.file);
+
+        if format!("{:x}", self.hasher.finalize()) != sha256 {
+            let _ = tokio::fs::remove_file(&self.partial_path).await;
+            return Err("The file hash does not match.".to_string());
+        }
+
+        let path = match (conflict, self.path.parent(), self.path.file_name()) {
+            (Some(policy), Some(dir), Some(name)) => {
+                let (name, _) =
+                    receive::target_name(dir, &name.to_string_lossy(), policy, |_| false)
+                        .ok_or("No name to save the file as.")?;
+                dir.join(name)
+            }
+            _ => self.path,
+        };
+        tokio::fs::rename(&self.partial_path, &path)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        Ok(path)
+    }
+
+    /// Keeps the partially written file on disk so the transfer can be resumed.
+    async fn suspend(mut self) {
+        let _ = self.file.flush().await;
+    }
+
+    /// Keeps the partially written file of a cancelled transfer, marked so offering the same
+    /// content again asks the user before resuming it.
+    async fn suspend_cancelled(self) {
+        let cancelled_path = Self::cancelled_path(&self.partial_path);
+        self.suspend().await;
+        let _ = tokio::fs::write(cancelled_path, "").await;
+    }
+
+    /// Removes the partially written file from disk.
+    async fn discard(self) {
+        drop(self.file);
+        let _ = tokio::fs::remove_file(&self.partial_path).await;
+        let _ = tokio::fs::remove_file(Self::cancelled_path(&self.partial_path)).await;
+    }
+}
+
+/// A folder being received from a peer, its files arriving one after the other in manifest order.
+struct IncomingFolder {
+    name: String,
+    root: PathBuf,
+    /// Where the folder's resume record is kept while it is being received.
+    record: PathBuf,
+    entries: Vec<ManifestEntry>,
+    total_size: u64,
+    /// The index of the entry being (or next to be) received.
+    next: usize,
+    /// How many files (in manifest order) an earlier transfer of the folder already received.
+    resumed: usize,
+    /// Whether the user accepted the folder, after which its files are no longer asked about.
+    accepted: bool,
+}
+
+/// What an unfinished folder transfer leaves next to the folder, so offering the same folder again
+/// resumes into it (an interruption between two files leaves no partial file to go by).
+#[derive(Serialize, Deserialize, Default)]
+struct FolderRecord {
+    /// How many files (in manifest order) were fully received.
+    received: usize,
+    /// Whether the user cancelled the transfer, so offering the folder again asks before resuming.
+    #[serde(default)]
+    cancelled: bool,
+}
+
+impl FolderRecord {
+    /// Returns where the record of a folder is kept, named after its manifest so only an offer of
+    /// the same content resumes it.
+    fn path(dir: &Path, name: &str, entries: &[ManifestEntry]) -> PathBuf {
+        let mut hasher = Sha256::new();
+        for entry in entries {
+            hasher.update(format!(
+                "{}\0{}\0{}\n",
+                entry.path, entry.size, entry.sha256
+            ));
+        }
+        let digest = format!("{:x}", hasher.finalize());
+
+        dir.join(format!("{}.{}.part", name, &digest[..16]))
+    }
+
+    async fn load(path: &Path) -> Option<Self> {
+        let contents = tokio::fs::read_to_string(path).await.ok()?;
+        serde_json::from_str(&contents).ok()
+    }
+
+    async fn save(&self, path: &Path) -> std::io::Result<()> {
+        let contents = serde_json::to_string(self)?;
+        tokio::fs::write(path, contents).await
+    }
+}
+
+/// Returns whether a file an earlier transfer of its folder received is still there, unchanged.
+async fn is_received(root: &Path, entry: &ManifestEntry) -> bool {
+    let Ok((path, _)) = IncomingFile::paths(root, &entry.path, &entry.sha256) else {
+        return false;
+    };
+    let same_size = tokio::fs::metadata(&path)
+        .await
+        .is_ok_and(|m| m.is_file() && m.len() == entry.size);
+    same_size
+        && hash_file(&path)
+            .await
+            .is_ok_and(|hash| hash == entry.sha256)
+}
+
+/// Finishes writing a file once its data stopped coming, returning where it was saved, or why it
+/// failed and whether the transfer can be resumed.
+async fn complete_file(
+    incoming: Option<IncomingFile>,
+    file_size: Option<u64>,
+    sha256: &str,
+    conflict: Option<ConflictPolicy>,
+) -> Result<PathBuf, (String, bool)> {
+    match incoming {
+        Some(incoming) if Some(incoming.received) == file_size => incoming
+            .finish(sha256, conflict)
+            .await
+            .map_err(|e| (e, false)),
+        // The transfer was interrupted, so keep what we have for the peer to resume.
+        Some(incoming) => {
+            incoming.suspend().await;
+            Err(("The transfer was interrupted.".to_string(), true))
+        }
+        None => Err(("The file could not be written.".to_string(), false)),
+    }
+}
+
+/// Records how the current file of an accepted folder was saved and lets the peer know the result.
+///
+/// Returns whether the folder is done with, either fully received or failed.
+async fn finish_folder_file<H, S>(
+    peer: SocketAddr,
+    host: &H,
+    ws_sender: &mut S,
+    folder: &mut IncomingFolder,
+    saved: Result<PathBuf, (String, bool)>,
+    active: Option<&mut H::Transfer>,
+) -> Result<bool>
+where
+    H: Host,
+    S: Sink<Message, Error = Error> + Unpin,
+{
+    let entry = &folder.entries[folder.next];
+    if let Ok(path) = &saved {
+        apply_mode(path, entry.mode).await;
+    }
+    ws_sender
+        .send(
+            P2pMessage::FileResult {
+                success: saved.is_ok(),
+            }
+            .into(),
+        )
+        .await?;
+
+    if let Err((error, _)) = saved {
+        let _ = tokio::fs::remove_file(&folder.record).await;
+        if let Some(active) = active {
+            active.finish(Outcome::Failed, None, Some(error.clone()));
+        }
+        host.report(
+            "folder_failed",
+            json!({
+                "folder_name": folder.name,
+                "file_name": entry.path,
+                "error": error,
+                "resumable": false,
+                "peer": peer
+            }),
+        );
+        return Ok(true);
+    }
+
+    folder.next += 1;
+    host.report(
+        "folder_progress",
+        json!({
+            "folder_name": folder.name,
+            "file_name": entry.path,
+            "files_received": folder.next,
+            "file_count": folder.entries.len(),
+            "peer": peer
+        }),
+    );
+
+    if folder.next < folder.entries.len() {
+        let record = FolderRecord {
+            received: folder.next.max(folder.resumed),
+            cancelled: false,
+        };
+        if let Err(e) = record.save(&folder.record).await {
+            host.log(format!(
+                "Failed saving the progress of {}: {}",
+                folder.name, e
+            ));
+        }
+        return Ok(false);
+    }
+
+    let _ = tokio::fs::remove_file(&folder.record).await;
+    if let Some(active) = active {
+        active.finish(Outcome::Completed, None, None);
+    }
+    host.report(
+        "folder_received",
+        json!({
+            "folder_name": folder.name,
+            "file_count": folder.entries.len(),
+            "path": folder.root,
+            "peer": peer
+        }),
+    );
+    Ok(true)
+}
+
+/// Waits for a command for the transfer being received, or forever if there is none.
+async fn next_command<T>(
+    transfer: &mut Option<(T, mpsc::UnboundedReceiver<TransferCommand>)>,
+) -> Option<TransferCommand> {
+    match transfer {
+        Some((_, commands)) => commands.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Listens on the address, taking an unspecified one (`0.0.0.0` or `::`) for all interfaces on
+/// both IPv6 and IPv4, unless the system has no IPv6.
+pub fn listen_on(address: SocketAddr) -> std::io::Result<TcpListener> {
+    if address.ip().is_unspecified() {
+        let dual_stack = SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), address.port());
+        match socket(dual_stack, false) {
+            Ok(listener) => return Ok(listener),
+            Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => return Err(e),
+            // Any other error means there is no IPv6 to listen on.
+            Err(_) => (),
+        }
+        return socket(
+            SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), address.port()),
+            false,
+        );
+    }
+
+    socket(address, true)
+}
+
+/// Creates a listening socket, which for IPv6 also accepts IPv4 connections unless `only_v6`.
+fn socket(address: SocketAddr, only_v6: bool) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(
+        Domain::for_address(address),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    if address.is_ipv6() {
+        socket.set_only_v6(only_v6)?;
+    }
+    // Like tokio does, so a restarted app can listen again right away (except on Windows,
+    // where it would let another process take the port).
+    #[cfg(not(windows))]
+    socket.set_reuse_address(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+
+    TcpListener::from_std(socket.into())
+}
+
+/// Closes the connection, telling the peer why.
+async fn close<S>(ws_sender: &mut S, code: CloseCode, reason: &str)
+where
+    S: Sink<Message, Error = Error> + Unpin,
+{
+    let frame = CloseFrame {
+        code,
+        reason: reason.to_string().into(),
+    };
+    let _ = ws_sender.send(Message::Close(Some(frame))).await;
+}
+
+/// Runs the handshake, returning the capabilities (and compression) both peers share.
+///
+/// Returns `None` when the connection should end there: the peer is not paired, failed to prove
+/// it is, uses another protocol version, or only came to pair.
+async fn handshake<H, S, R>(
+    peer: SocketAddr,
+    host: &H,
+    session: &Session,
+    ws_sender: &mut S,
+    ws_receiver: &mut R,
+) -> Result<Option<Negotiated>>
+where
+    H: Host,
+    S: Sink<Message, Error = Error> + Unpin,
+    R: Stream<Item = Result<Message>> + Unpin,
+{
+    let devices = host.devices();
+    let (my_id, my_name) = devices.identity();
+
+    let Ok(Ok(msg)) = tokio::time::timeout(HANDSHAKE_TIMEOUT, next_message(ws_receiver)).await
+    else {
+        return Ok(None);
+    };
+
+    let (capabilities, compressions, device_id, device_name, port) = match msg {
+        // Anyone can ask who we are (discovery already announces it), whatever their version.
+        P2pMessage::Probe => {
+            let identity = P2pMessage::Identity {
+                device_id: my_id,
+                device_name: my_name,
+                version: PROTOCOL_VERSION,
+            };
+            ws_sender.send(identity.into()).await?;
+            return Ok(None);
+        }
+        P2pMessage::Hello { version, .. } | P2pMessage::PairRequest { version, .. }
+            if version != PROTOCOL_VERSION =>
+        {
+            let message = format!(
+                "Protocol version {} is not supported (expected {}).",
+                version, PROTOCOL_VERSION
+            );
+            ws_sender
+                .send(P2pMessage::error(ErrorCode::UnsupportedVersion, message).into())
+                .await?;
+            return Ok(None);
+        }
+        P2pMessage::PairRequest {
+            device_id,
+            device_name,
+            nonce,
+            token,
+            ..
+        } => {
+            let request = PairRequest {
+                device_id,
+                device_name,
+                nonce,
+                token,
+            };
+            pair(peer, host, session, ws_sender, request).await?;
+            return Ok(None);
+        }
+        P2pMessage::Hello {
+            capabilities,
+            device_id,
+            device_name,
+            compressions,
+            port,
+            ..
+        } => (capabilities, compressions, device_id, device_name, port),
+        _ => {
+            let error =
+                P2pMessage::error(ErrorCode::HandshakeRequired, "Expected a hello message.");
+            ws_sender.send(error.into()).await?;
+            return Ok(None);
+        }
+    };
+
+    // Unpaired devices are turned away before anything else.
+    let Some(secret) = devices.secret(&device_id) else {
+        host.log(format!("Rejected unpaired peer {} ({})", peer, device_name));
+        host.report(
+            "peer_rejected",
+            json!({"device_name": device_name, "peer": peer}),
+        );
+        let error = P2pMessage::error(ErrorCode::Unpaired, "This device is not paired.");
+        ws_sender.send(error.into()).await?;
+        return Ok(None);
+    };
+
+    // The device must use the key pinned when pairing, or someone else is using its identity.
+    if !devices.check_key(&device_id, &session.remote_key) {
+        host.log(format!(
+            "Peer {} ({}) used an unknown key.",
+            peer, device_name
+        ));
+        host.report(
+            "peer_rejected",
+            json!({"device_name": device_name, "peer": peer}),
+        );
+        let error = P2pMessage::error(ErrorCode::AuthFailed, "Authentication failed.");
+        ws_sender.send(error.into()).await?;
+        return Ok(None);
+    }
+
+    // Make the peer prove it knows the secret shared when pairing.
+    let nonce = random_hex(16);
+    ws_sender
+        .send(
+            P2pMessage::AuthChallenge {
+                device_id: my_id,
+                device_name: my_name,
+                nonce: nonce.clone(),
+            }
+            .into(),
+        )
+        .await?;
+    let proof = match tokio::time::timeout(HANDSHAKE_TIMEOUT, next_message(ws_receiver)).await {
+        Ok(Ok(P2pMessage::AuthResponse { proof })) => proof,
+        _ => String::new(),
+    };
+    if !check_proof(&secret, &nonce, &proof) {
+        host.log(format!("Peer {} failed authentication.", peer));
+        let error = P2pMessage::error(ErrorCode::AuthFailed, "Authentication failed.");
+        ws_sender.send(error.into()).await?;
+        return Ok(None);
+    }
+
+    let negotiated = Negotiated {
+        capabilities: shared_capabilities(&capabilities),
+        compression: compression::negotiate(&compressions),
+    };
+    ws_sender
+        .send(
+            P2pMessage::Welcome {
+                version: PROTOCOL_VERSION,
+                capabilities: negotiated.capabilities.clone(),
+                compression: negotiated.compression,
+            }
+            .into(),
+        )
+        .await?;
+    // The peer's own server is at the same (possibly scoped) address, on the port it told us.
+    if let Some(port) = port {
+        let mut address = peer;
+        address.set_port(port);
+        devices.set_address(&device_id, address.to_string());
+    }
+    host.identify(peer, device_id, device_name);
+
+    Ok(Some(negotiated))
+}
+
+/// A device asking to pair.
+struct PairRequest {
+    device_id: String,
+    device_name: String,
+    nonce: String,
+    token: Option<String>,
+}
+
+/// Handles a pair request, asking the user to confirm the code shown on both devices.
+///
+/// A request with a token from this device's connection card uses the token up, and is declined
+/// right away if it is not valid (anymore). Requests without one are too, when only pairing by
+/// card.
+async fn pair<H, S>(
+    peer: SocketAddr,
+    host: &H,
+    session: &Session,
+    ws_sender: &mut S,
+    request: PairRequest,
+) -> Result<()>
+where
+    H: Host,
+    S: Sink<Message, Error = Error> + Unpin,
+{
+    let devices = host.devices();
+    let (my_id, my_name) = devices.identity();
+    let nonce = random_hex(16);
+    ws_sender
+        .send(
+            P2pMessage::PairChallenge {
+                device_id: my_id,
+                device_name: my_name,
+                nonce: nonce.clone(),
+            }
+            .into(),
+        )
+        .await?;
+
+    let PairRequest {
+        device_id,
+        device_name,
+        nonce: peer_nonce,
+        token,
+    } = request;
+
+    // Ask the user to accept if the code matches the one on the other device, telling them when the
+    // request comes from their connection card.
+    let card = token.is_some();
+    let accept = if token.is_some_and(|token| !host.redeem_token(&token)) {
+        host.log(format!("Peer {} used an invalid pairing token.", peer));
+        false
+    } else if !card && host.p2p_config().card_pairing_only {
+        host.log(format!(
+            "Peer {} did not pair with our connection card.",
+            peer
+        ));
+        false
+    } else {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let _prompt = host.ask(
+            "pair_request",
+            json!({
+                "code": pairing_code(&session.session_id, &peer_nonce, &nonce),
+                "device_name": device_name,
+                "card": card,
+                "peer": peer
+            }),
+            sender,
+        );
+        tokio::time::timeout(ASK_FILE_TIMEOUT, receiver.recv())
+            .await
+            .ok()
+            .flatten()
+            .unwrap_or(false)
+    };
+
+    if !accept {
+        host.report("pair_declined", json!({ "peer": peer }));
+        ws_sender
+            .send(
+                P2pMessage::PairResult {
+                    accepted: false,
+                    secret: String::new(),
+                }
+                .into(),
+            )
+            .await?;
+        return Ok(());
+    }
+
+    let secret = random_hex(32);
+    devices.trust(
+        device_id.clone(),
+        device_name.clone(),
+        secret.clone(),
+        session.remote_key.clone(),
+    );
+    ws_sender
+        .send(
+            P2pMessage::PairResult {
+                accepted: true,
+                secret,
+            }
+            .into(),
+        )
+        .await?;
+    host.report(
+        "paired",
+        json!({"device_id": device_id, "device_name": device_name, "peer": peer}),
+    );
+
+    Ok(())
+}
+
+/// Whether the value is a hex encoded SHA-256 hash (it ends up in a file name, so be strict).
+fn is_sha256(value: &str) -> bool {
+    value.len() == 64 && value.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Turns a `/` separated path from a peer into a relative one, or `None` if it could end up
+/// outside the folder it is written to (absolute paths, `..`, drive letters...) or has a part that
+/// is not a safe name on every platform (reserved names, trailing dots...).
+fn safe_relative_path(path: &str) -> Option<PathBuf> {
+    let mut safe = PathBuf::new();
+    for part in path.split('/') {
+        if receive::sanitize_name(part).as_deref() != Some(part) {
+            return None;
+        }
+
+        safe.push(part);
+    }
+
+    // Whatever the platform treats specially (prefixes, roots...) is refused too.
+    safe.components()
+        .all(|c| matches!(c, Component::Normal(_)))
+        .then_some(safe)
+}
+
+/// Checks a folder offer, returning why it is refused.
+fn check_folder(name: &str, entries: &[ManifestEntry]) -> Result<(), String> {
+    if name.contains('/') || safe_relative_path(name).is_none() {
+        return Err("Invalid folder name.".to_string());
+    }
+
+    if entries.len() > MAX_FOLDER_ENTRIES {
+        return Err(format!(
+            "Folders can't have more than {} files.",
+            MAX_FOLDER_ENTRIES
+        ));
+    }
+
+    let mut paths = std::collections::HashSet::new();
+    for entry in entries {
+        if safe_relative_path(&entry.path).is_none() {
+            return Err(format!("Invalid path in the folder: {}", entry.path));
+        }
+
+        if !is_sha256(&entry.sha256) {
+            return Err(format!("Invalid file hash for {}", entry.path));
+        }
+
+        if !paths.insert(entry.path.as_str()) {
+            return Err(format!("Duplicate path in the folder: {}", entry.path));
+        }
+    }
+
+    Ok(())
+}
+
+/// Applies the unix permission bits sent with a folder entry (never setuid, setgid or sticky).
+#[cfg(unix)]
+async fn apply_mode(path: &Path, mode: Option<u32>) {
+    use std::os::unix::fs::PermissionsExt;
+
+    if let Some(mode) = mode {
+        let permissions = std::fs::Permissions::from_mode(mode & 0o777);
+        let _ = tokio::fs::set_permissions(path, permissions).await;
+    }
+}
+
+#[cfg(not(unix))]
+async fn apply_mode(_path: &Path, _mode: Option<u32>) {}
+
+/// Handles a connection from a peer until it ends: the encryption and handshake first, then the
+/// files, folders and texts it sends.
+pub async fn handle_connection<H: Host>(
+    peer: SocketAddr,
+    stream: TcpStream,
+    host: &H,
+    mut commands: mpsc::UnboundedReceiver<PeerCommand>,
+) -> Result<()> {
+    let ws_stream = accept_async(stream).await?;
+    host.log(format!("New WebSocket connection: {}", peer));
+    let (ws_sender, ws_receiver) = ws_stream.split();
+
+    // Everything after the WebSocket upgrade goes through the encrypted channel.
+    let private_key = host.devices().private_key();
+    let secure = tokio::time::timeout(
+        HANDSHAKE_TIMEOUT,
+        secure::respond(&private_key, ws_sender, ws_receiver),
+    )
+    .await
+    .unwrap_or(Err("Timed out.".to_string()));
+    let (mut ws_sender, mut ws_receiver, session) = match secure {
+        Ok(secure) => secure,
+        Err(e) => {
+            host.log(format!("Encryption handshake with {} failed: {}", peer, e));
+            return Ok(());
+        }
+    };
+    let mut result = Ok(());
+
+    // Nothing is accepted until the peer is paired and has completed the handshake.
+    let Some(Negotiated {
+        capabilities,
+        compression: negotiated_compression,
+    }) = handshake(peer, host, &session, &mut ws_sender, &mut ws_receiver).await?
+    else {
+        let _ = ws_sender.close().await;
+        return Ok(());
+    };
+
+    // File variables.
+    let mut file_name = "".to_string();
+    let mut file_size: Option<u64> = None;
+    let mut file_sha256 = "".to_string();
+    let mut file_replaces = false;
+    let mut incoming_file: Option<IncomingFile> = None;
+    let mut accepted = false;
+
+    // Compression variables (the bytes counted to report the ratio).
+    let mut file_compression: Option<Compression> = None;
+    let mut wire_bytes: u64 = 0;
+    let mut data_bytes: u64 = 0;
+
+    // Folder variables (its files come in as file offers once the folder is accepted).
+    let mut folder: Option<IncomingFolder> = None;
+
+    // Transfer variables (registered once accepted, so the app can pause or cancel it).
+    let mut transfer: Option<(H::Transfer, mpsc::UnboundedReceiver<TransferCommand>)> = None;
+    let mut cancelled_by_peer: Option<bool> = None;
+
+    // Keepalive variables (unanswered pings, and when the connection last saw any activity).
+    let config = host.p2p_config();
+    let ping_interval = std::time::Duration::from_secs(config.ping_interval.max(1));
+    let mut ping =
+        tokio::time::interval_at(tokio::time::Instant::now() + ping_interval, ping_interval);
+    let mut missed_pongs = 0;
+    let idle_timeout = std::time::Duration::from_secs(config.idle_timeout);
+    let idle = tokio::time::sleep(idle_timeout);
+    tokio::pin!(idle);
+
+    // Ask variables (the user's decision comes back through the channel).
+    let (ask_sender, mut ask_receiver) = mpsc::unbounded_channel::<bool>();
+    let mut ask_prompt = None;
+    let mut awaiting_answer = false;
+    let ask_timeout = tokio::time::sleep(ASK_FILE_TIMEOUT);
+    tokio::pin!(ask_timeout);
+
+    loop {
+        tokio::select! {
+            msg = ws_receiver.next() => {
+                match msg {
+                    Some(Ok(msg)) => {
+                        if msg.is_close() {
+                            break;
+                        }
+
+                        // Pongs only prove the peer is still there, they are not activity.
+                        if msg.is_pong() {
+                            missed_pongs = 0;
+                            continue;
+                        }
+                        if msg.is_ping() {
+                            continue;
+                        }
+                        idle.as_mut().reset(tokio::time::Instant::now() + idle_timeout);
+
+                        // Binary data is only expected while receiving an accepted file.
+                        if msg.is_binary() {
+                            let (Some(incoming), Some(expected_size)) = (incoming_file.as_mut(), file_size) else {
+                                continue;
+                            };
+
+                            let frame = msg.into_data();
+                            wire_bytes += frame.len() as u64;
+                            let data = match compression::decode_chunk(file_compression, frame) {
+                                Ok(data) => data,
+                                Err(e) => {
+                                    host.log(format!("Invalid data from peer {}: {}", peer, e));
+                                    break;
+                                }
+                            };
+                            data_bytes += data.len() as u64;
+
+                            // Make sure the peer does not send more than it announced.
+                            incoming.received += data.len() as u64;
+                            if incoming.received > expected_size {
+                                host.log(format!("Peer {} sent more than the announced {} bytes.", peer, expected_size));
+                                if let Some(incoming) = incoming_file.take() {
+                                    incoming.discard().await;
+                                }
+                                break;
+                            }
+
+                            if let Err(e) = incoming.file.write_all(&data).await {
+                                host.log(format!("Failed writing file {}: {}", file_name, e));
+                                break;
+                            }
+                            incoming.hasher.update(&data);
+
+                            // The ratio is only worth showing for compressed data.
+                            let ratio = file_compression.map(|_| compression::ratio(data_bytes, wire_bytes));
+                            if let (Some((active, _)), Some(ratio)) = (transfer.as_ref(), ratio) {
+                                host.record_compression_ratio(active.id(), ratio);
+                            }
+                            host.report("file_progress", json!({
+                                "transfer_id": transfer.as_ref().map(|(t, _)| t.id()),
+                                "file_name": file_name,
+                                "file_size": expected_size,
+                                "received": incoming.received,
+                                "compression_ratio": ratio,
+                                "peer": peer
+                            }));
+
+                            // All the data has been received.
+                            if incoming.received == expected_size {
+                                let Some(incoming_folder) = folder.as_mut() else {
+                                    break;
+                                };
+
+                                // Files in a folder are finished one by one, and the next one follows.
+                                accepted = false;
+                                file_size = None;
+                                let entry = &incoming_folder.entries[incoming_folder.next];
+                                let saved = complete_file(incoming_file.take(), Some(entry.size), &entry.sha256, None).await;
+                                let active = transfer.as_mut().map(|(t, _)| t);
+                                match finish_folder_file(peer, host, &mut ws_sender, incoming_folder, saved, active).await {
+                                    Ok(false) => (),
+                                    Ok(true) => break,
+                                    Err(e) => {
+                                        result = Err(e);
+                                        break;
+                                    }
+                                }
+                            }
+
+                            continue;
+                        }
+
+                        let parsed = match P2pMessage::from_ws(&msg) {
+                            Some(Ok(parsed)) => parsed,
+                            Some(Err(e)) => {
+                                host.log(format!("Invalid msg sent by {}: {}", peer, e));
+                                let error = P2pMessage::error(ErrorCode::InvalidMessage, e.to_string());
+                                if let Err(e) = ws_sender.send(error.into()).await {
+                                    result = Err(e);
+                                    break;
+                                }
+                                continue;
+                            }
+                            None => continue,
+                        };
+
+                        let reply = match parsed {
+                            // Ask the user if we want the file.
+                            P2pMessage::FileOffer { .. } if !capabilities.contains(&Capability::File) => {
+                                Some(P2pMessage::error(ErrorCode::UnsupportedCapability, "File transfers were not negotiated."))
+                            }
+                            P2pMessage::FileOffer { .. } if file_size.is_some() => {
+                                Some(P2pMessage::error(ErrorCode::UnexpectedMessage, "A file has already been offered."))
+                            }
+                            P2pMessage::FileOffer { sha256, .. } if !is_sha256(&sha256) => {
+                                Some(P2pMessage::error(ErrorCode::InvalidMessage, "Invalid file hash."))
+                            }
+                            P2pMessage::FileOffer { compression: Some(c), .. } if Some(c) != negotiated_compression => {
+                                Some(P2pMessage::error(ErrorCode::UnsupportedCapability, "The compression was not negotiated."))
+                            }
+
+                            // Files of an accepted folder must follow its manifest, and are not asked about.
+                            P2pMessage::FileOffer { .. } if folder.as_ref().is_some_and(|f| !f.accepted) => {
+                                Some(P2pMessage::error(ErrorCode::UnexpectedMessage, "The folder has not been accepted."))
+                            }
+                            P2pMessage::FileOffer { name, size, sha256, compression } if folder.is_some() => {
+                                let incoming_folder = folder.as_ref().expect("checked by the guard");
+                                let expected = incoming_folder.entries.get(incoming_folder.next);
+                                if !expected.is_some_and(|e| e.path == name && e.size == size && e.sha256 == sha256) {
+                                    Some(P2pMessage::error(ErrorCode::InvalidMessage, "The file is not the next one in the folder."))
+                                } else if incoming_folder.next < incoming_folder.resumed && is_received(&incoming_folder.root, &incoming_folder.entries[incoming_folder.next]).await {
+                                    // Files an earlier transfer of the folder received are skipped.
+                                    if let Err(e) = ws_sender.send(P2pMessage::FileAnswer { accept: true, offset: size }.into()).await {
+                                        result = Err(e);
+                                        break;
+                                    }
+
+                                    let incoming_folder = folder.as_mut().expect("checked by the guard");
+                                    let saved = IncomingFile::paths(&incoming_folder.root, &name, &sha256)
+                                        .map(|(path, _)| path)
+                                        .map_err(|e| (e.to_string(), false));
+                                    let active = transfer.as_mut().map(|(t, _)| t);
+                                    match finish_folder_file(peer, host, &mut ws_sender, incoming_folder, saved, active).await {
+                                        Ok(false) => (),
+                                        Ok(true) => break,
+                                        Err(e) => {
+                                            result = Err(e);
+                                            break;
+                                        }
+                                    }
+                                    None
+                                } else {
+                                    file_name = name;
+                                    file_sha256 = sha256;
+                                    file_compression = compression;
+                                    wire_bytes = 0;
+                                    data_bytes = 0;
+
+                                    let incoming = match IncomingFile::open(&incoming_folder.root, &file_name, &file_sha256, size).await {
+                                        Ok(incoming) => incoming,
+                                        Err(e) => {
+                                            host.log(format!("Failed opening file {}: {}", file_name, e));
+                                            break;
+                                        }
+                                    };
+                                    let offset = incoming.received;
+                                    if let Err(e) = ws_sender.send(P2pMessage::FileAnswer { accept: true, offset }.into()).await {
+                                        result = Err(e);
+                                        break;
+                                    }
+
+                                    // An empty (or already fully written) file is done straight away.
+                                    if offset == size {
+                                        let saved = complete_file(Some(incoming), Some(size), &file_sha256, None).await;
+                                        let incoming_folder = folder.as_mut().expect("checked by the guard");
+                                        let active = transfer.as_mut().map(|(t, _)| t);
+                                        match finish_folder_file(peer, host, &mut ws_sender, incoming_folder, saved, active).await {
+                                            Ok(false) => (),
+                                            Ok(true) => break,
+                                            Err(e) => {
+                                                result = Err(e);
+                                                break;
+                                            }
+                                        }
+                                    } else {
+                                        incoming_file = Some(incoming);
+                                        file_size = Some(size);
+                                        accepted = true;
+                                    }
+                                    None
+                                }
+                            }
+
+                            P2pMessage::FileOffer { name, size, sha256, compression } => {
+                                let dir = match host.receive_dir() {
+                                    Ok(dir) => dir,
+                                    Err(e) => {
+                                        host.log(format!("No folder to save files to: {}", e));
+                                        break;
+                                    }
+                                };
+
+                                // The announced name is only a suggestion, made safe and free (per the policy).
+                                let policy = host.p2p_config();
+                                let target = receive::sanitize_name(&name)
+                                    .and_then(|name| receive::target_name(&dir, &name, policy.name_conflict, |_| false));
+                                let Some((name, exists)) = target else {
+                                    let reason = "No name to save the file as.";
+                                    host.report("file_refused", json!({"file_name": name, "error": reason, "peer": peer}));
+                                    let error = P2pMessage::error(ErrorCode::Refused, reason);
+                                    if let Err(e) = ws_sender.send(error.into()).await {
+                                        result = Err(e);
+                                        break;
+                                    }
+                                    continue;
+                                };
+
+                                // Offers going against the policy are refused without asking.
+                                let existing = IncomingFile::existing_len(&dir, &name, &sha256).await;
+                                if let Err(reason) = receive::check_offer(&policy, &dir, size, size.saturating_sub(existing)) {
+                                    host.report("file_refused", json!({
+                                        "file_name": name,
+                                        "file_size": size,
+                                        "error": reason,
+                                        "peer": peer
+                                    }));
+                                    let error = P2pMessage::error(ErrorCode::Refused, reason);
+                                    if let Err(e) = ws_sender.send(error.into()).await {
+                                        result = Err(e);
+                                        break;
+                                    }
+                                    continue;
+                                }
+
+                                file_name = name;
+                                file_size = Some(size);
+                                file_sha256 = sha256;
+                                file_replaces = exists;
+                                file_compression = compression;
+                                wire_bytes = 0;
+                                data_bytes = 0;
+                                awaiting_answer = true;
+                                while ask_receiver.try_recv().is_ok() {}
+
+                                // A transfer the user already accepted is resumed without asking again, unless
+                                // they cancelled it.
+                                if existing > 0 && !IncomingFile::was_cancelled(&dir, &file_name, &file_sha256).await {
+                                    host.report("file_resumed", json!({
+                                        "file_name": file_name,
+                                        "file_size": size,
+                                        "received": existing,
+                                        "peer": peer
+                                    }));
+                                    let _ = ask_sender.send(true);
+                                    continue;
+                                }
+
+                                // Wait for the file response, only listening to answers for this peer.
+                                ask_prompt = Some(host.ask("ask_file", json!({
+                                    "file_name": file_name,
+                                    "file_size": size,
+                                    "received": existing,
+                                    "exists": exists,
+                                    "peer": peer
+                                }), ask_sender.clone()));
+                                ask_timeout.as_mut().reset(tokio::time::Instant::now() + ASK_FILE_TIMEOUT);
+                                None
+                            }
+
+                            // Ask the user if we want the folder (all of its files at once).
+                            P2pMessage::FolderOffer { .. } if !capabilities.contains(&Capability::Folder) => {
+                                Some(P2pMessage::error(ErrorCode::UnsupportedCapability, "Folder transfers were not negotiated."))
+                            }
+                            P2pMessage::FolderOffer { .. } if file_size.is_some() || folder.is_some() => {
+                                Some(P2pMessage::error(ErrorCode::UnexpectedMessage, "A file has already been offered."))
+                            }
+                            P2pMessage::FolderOffer { name, entries } => match check_folder(&name, &entries) {
+                                Err(e) => Some(P2pMessage::error(ErrorCode::InvalidMessage, e)),
+                                Ok(()) => {
+                                    let dir = match host.receive_dir() {
+                                        Ok(dir) => dir,
+                                        Err(e) => {
+                                            host.log(format!("No folder to save files to: {}", e));
+                                            break;
+                                        }
+                                    };
+
+                                    // A folder an earlier transfer of this one left unfinished is resumed, others are handled per the policy.
+                                    let policy = host.p2p_config();
+                                    let resumes = |candidate: &str| FolderRecord::path(&dir, candidate, &entries).exists();
+                                    let total_size = entries.iter().try_fold(0u64, |total, e| total.checked_add(e.size));
+                                    let checked = match (receive::target_name(&dir, &name, policy.name_conflict, resumes), total_size) {
+                                        (_, None) => Err("The folder is too big.".to_string()),
+                                        (Some(target), Some(total_size)) => receive::check_offer(&policy, &dir, total_size, total_size).map(|()| target),
+                                        (None, _) => Err("No name to save the folder as.".to_string()),
+                                    };
+                                    let (name, exists) = match checked {
+                                        Ok(target) => target,
+                                        Err(reason) => {
+                                            host.report("folder_refused", json!({
+                                                "folder_name": name,
+                                                "total_size": total_size,
+                                                "error": reason,
+                                                "peer": peer
+                                            }));
+                                            let error = P2pMessage::error(ErrorCode::Refused, reason);
+                                            if let Err(e) = ws_sender.send(error.into()).await {
+                                                result = Err(e);
+                                                break;
+                                            }
+                                            continue;
+                                        }
+                                    };
+
+                                    let root = dir.join(&name);
+                                    let record = FolderRecord::path(&dir, &name, &entries);
+                                    let resumed = FolderRecord::load(&record).await;
+                                    let incoming_folder = folder.insert(IncomingFolder {
+                                        name,
+                                        root,
+                                        record,
+                                        total_size: total_size.unwrap_or_default(),
+                                        next: 0,
+                                        resumed: resumed.as_ref().map_or(0, |r| r.received.min(entries.len())),
+                                        entries,
+                                        accepted: false,
+                                    });
+                                    awaiting_answer = true;
+                                    while ask_receiver.try_recv().is_ok() {}
+
+                                    // A folder the user already accepted is resumed without asking again, unless
+                                    // they cancelled it.
+                                    if resumed.as_ref().is_some_and(|r| !r.cancelled) {
+                                        host.report("folder_resumed", json!({
+                                            "folder_name": incoming_folder.name,
+                                            "file_count": incoming_folder.entries.len(),
+                                            "files_received": incoming_folder.resumed,
+                                            "peer": peer
+                                        }));
+                                        let _ = ask_sender.send(true);
+                                        continue;
+                                    }
+
+                                    ask_prompt = Some(host.ask("ask_folder", json!({
+                                        "folder_name": incoming_folder.name,
+                                        "file_count": incoming_folder.entries.len(),
+                                        "total_size": incoming_folder.total_size,
+                                        "files_received": incoming_folder.resumed,
+                                        "exists": exists,
+                                        "peer": peer
+                                    }), ask_sender.clone()));
+                                    ask_timeout.as_mut().reset(tokio::time::Instant::now() + ASK_FILE_TIMEOUT);
+                                    None
+                                }
+                            },
+
+                            // Show (or copy) the text.
+                            P2pMessage::Text { .. } if !capabilities.contains(&Capability::Text) => {
+                                Some(P2pMessage::error(ErrorCode::UnsupportedCapability, "Text messages were not negotiated."))
+                            }
+                            P2pMessage::Text { compression: Some(c), .. } if Some(c) != negotiated_compression => {
+                                Some(P2pMessage::error(ErrorCode::UnsupportedCapability, "The compression was not negotiated."))
+                            }
+                            P2pMessage::Text { text, clipboard, compression } => {
+                                let text = match compression {
+                                    Some(_) => compression::decompress_text(&text),
+                                    None => Ok(text),
+                                };
+
+                                match text {
+                                    Err(e) => Some(P2pMessage::error(ErrorCode::InvalidMessage, e.to_string())),
+                                    Ok(text) => {
+                                        host.text_received(peer, text, clipboard);
+                                        None
+                                    }
+                                }
+                            }
+
+                            // The peer paused, resumed or cancelled the transfer.
+                            P2pMessage::TransferControl { action: TransferCommand::Cancel } if transfer.is_some() => {
+                                cancelled_by_peer = Some(true);
+                                break;
+                            }
+                            P2pMessage::TransferControl { action } if transfer.is_some() => {
+                                let (active, _) = transfer.as_ref().expect("checked by the guard");
+                                host.report_paused(active.id(), action == TransferCommand::Pause, true);
+                                None
+                            }
+
+                            P2pMessage::Error { code, message } => {
+                                host.log(format!("Peer {} sent an error ({:?}): {}", peer, code, message));
+                                None
+                            }
+                            _ => Some(P2pMessage::error(ErrorCode::UnexpectedMessage, "Unexpected message.")),
+                        };
+
+                        if let Some(reply) = reply {
+                            if let Err(e) = ws_sender.send(reply.into()).await {
+                                result = Err(e);
+                                break;
+                            }
+                        }
+                    }
+                    Some(Err(e)) => {
+                        result = Err(e);
+                        break;
+                    }
+                    None => break,
+                }
+            }
+            Some(accept) = ask_receiver.recv(), if awaiting_answer => {
+                awaiting_answer = false;
+                idle.as_mut().reset(tokio::time::Instant::now() + idle_timeout);
+                ask_prompt = None;
+
+                // A folder is only answered once, its files then follow without asking.
+                if let Some(incoming_folder) = folder.as_mut() {
+                    if !accept {
+                        folder = None;
+                    } else if let Err(e) = tokio::fs::create_dir_all(&incoming_folder.root).await {
+                        host.log(format!("Failed creating folder {}: {}", incoming_folder.name, e));
+                        break;
+                    } else {
+                        // Written before any file comes in, so the folder is resumed whenever it is interrupted.
+                        if !incoming_folder.entries.is_empty() {
+                            let record = FolderRecord { received: incoming_folder.resumed, cancelled: false };
+                            if let Err(e) = record.save(&incoming_folder.record).await {
+                                host.log(format!("Failed saving the progress of {}: {}", incoming_folder.name, e));
+                            }
+                        }
+                        incoming_folder.accepted = true;
+                        transfer = Some(host.start_transfer(
+                            Direction::Receive,
+                            &incoming_folder.name,
+                            incoming_folder.total_size,
+                            &peer.to_string(),
+                        ));
+                    }
+
+                    if let Err(e) = ws_sender.send(P2pMessage::FileAnswer { accept, offset: 0 }.into()).await {
+                        result = Err(e);
+                        break;
+                    }
+
+                    // Nothing more to receive for an empty folder.
+                    if let Some(incoming_folder) = folder.as_ref().filter(|f| f.entries.is_empty()) {
+                        if let Some((active, _)) = transfer.as_mut() {
+                            active.finish(Outcome::Completed, None, None);
+                        }
+                        host.report("folder_received", json!({
+                            "folder_name": incoming_folder.name,
+                            "file_count": 0,
+                            "path": incoming_folder.root,
+                            "peer": peer
+                        }));
+                        break;
+                    }
+                    continue;
+                }
+
+                // If not allowed, reset the file and let the peer know.
+                if !accept {
+                    file_name = "".to_string();
+                    file_size = None;
+                    if let Err(e) = ws_sender.send(P2pMessage::FileAnswer { accept: false, offset: 0 }.into()).await {
+                        result = Err(e);
+                        break;
+                    }
+                    continue;
+                }
+
+                // Open the (possibly partial) file on disk to stream the binary data into.
+                accepted = true;
+                let opened = match host.receive_dir() {
+                    Ok(dir) => IncomingFile::open(&dir, &file_name, &file_sha256, file_size.unwrap_or(0)).await,
+                    Err(e) => Err(e),
+                };
+                let offset = match opened {
+                    Ok(incoming) => incoming_file.insert(incoming).received,
+                    Err(e) => {
+                        host.log(format!("Failed opening file {}: {}", file_name, e));
+                        break;
+                    }
+                };
+                transfer = Some(host.start_transfer(
+                    Direction::Receive,
+                    &file_name,
+                    file_size.unwrap_or(0),
+                    &peer.to_string(),
+                ));
+
+                // If allowed, send the event to the peer that we are good to send (from the offset).
+                if let Err(e) = ws_sender.send(P2pMessage::FileAnswer { accept: true, offset }.into()).await {
+                    result = Err(e);
+                    break;
+                }
+
+                // Nothing more to receive for an empty (or already fully written) file.
+                if Some(offset) == file_size {
+                    break;
+                }
+            }
+            _ = &mut ask_timeout, if ask_prompt.is_some() => {
+                awaiting_answer = false;
+                ask_prompt = None;
+
+                // Nobody answered, so treat it as declined.
+                host.report("ask_file_expired", json!({
+                    "file_name": folder.as_ref().map_or(&file_name, |f| &f.name),
+                    "peer": peer
+                }));
+                file_name = "".to_string();
+                file_size = None;
+                folder = None;
+                if let Err(e) = ws_sender.send(P2pMessage::FileAnswer { accept: false, offset: 0 }.into()).await {
+                    result = Err(e);
+                    break;
+                }
+            }
+            Some(command) = next_command(&mut transfer) => {
+                // Let the peer know, so it stops (or starts again) sending.
+                if let Err(e) = ws_sender.send(P2pMessage::TransferControl { action: command }.into()).await {
+                    result = Err(e);
+                    break;
+                }
+
+                if command == TransferCommand::Cancel {
+                    cancelled_by_peer = Some(false);
+                    break;
+                }
+
+                if let Some((active, _)) = transfer.as_ref() {
+                    host.report_paused(active.id(), command == TransferCommand::Pause, false);
+                }
+            }
+            Some(command) = commands.recv() => {
+                match command {
+                    PeerCommand::Disconnect => {
+                        host.log(format!("Disconnecting peer: {}", peer));
+                        break;
+                    }
+                }
+            }
+            _ = ping.tick() => {
+                if missed_pongs >= config.max_missed_pongs.max(1) {
+                    host.log(format!("Peer {} stopped answering pings.", peer));
+                    close(&mut ws_sender, CloseCode::Away, "No answer to pings.").await;
+                    break;
+                }
+
+                if let Err(e) = ws_sender.send(Message::Ping(Vec::new())).await {
+                    result = Err(e);
+                    break;
+                }
+                missed_pongs += 1;
+            }
+            _ = &mut idle, if config.idle_timeout > 0 && transfer.is_none() && !awaiting_answer => {
+                host.log(format!("Closing idle connection: {}", peer));
+                close(&mut ws_sender, CloseCode::Normal, "Idle for too long.").await;
+                break;
+            }
+        }
+    }
+
+    // A cancelled transfer's partial file is kept or deleted depending on the policy. A kept one
+    // is marked as cancelled (for a folder, in its record), so it is only resumed once the user
+    // accepts it again.
+    if let Some(by_peer) = cancelled_by_peer {
+        accepted = false;
+        if let Some((active, _)) = transfer.as_mut() {
+            let sha256 = folder.is_none().then(|| file_sha256.clone());
+            active.finish(Outcome::Cancelled, sha256, None);
+        }
+        let policy = host.p2p_config().partial_policy;
+        match (incoming_file.take(), policy) {
+            (Some(incoming), PartialPolicy::Delete) => incoming.discard().await,
+            (Some(incoming), PartialPolicy::Keep) if folder.is_none() => {
+                incoming.suspend_cancelled().await
+            }
+            (Some(incoming), PartialPolicy::Keep) => incoming.suspend().await,
+            (None, _) => (),
+        }
+        if let Some(incoming_folder) = folder.as_ref() {
+            match policy {
+                PartialPolicy::Delete => {
+                    let _ = tokio::fs::remove_file(&incoming_folder.record).await;
+                }
+                PartialPolicy::Keep => {
+                    let record = FolderRecord {
+                        received: incoming_folder.next.max(incoming_folder.resumed),
+                        cancelled: true,
+                    };
+                    if let Err(e) = record.save(&incoming_folder.record).await {
+                        host.log(format!(
+                            "Failed saving the progress of {}: {}",
+                            incoming_folder.name, e
+                        ));
+                    }
+                }
+            }
+        }
+
+        host.report(
+            "transfer_cancelled",
+            json!({
+                "transfer_id": transfer.as_ref().map(|(t, _)| t.id()),
+                "name": folder.as_ref().map_or(&file_name, |f| &f.name),
+                "by_peer": by_peer,
+                "peer": peer
+            }),
+        );
+        let _ = ws_sender.close().await;
+    }
+
+    // If a file was being received, finish it up and let the peer know the result.
+    if accepted {
+        // Only replace a file taking the name meanwhile if the policy says so (and the user was
+        // told, when asked).
+        let conflict = folder
+            .is_none()
+            .then(|| match host.p2p_config().name_conflict {
+                ConflictPolicy::Ask if !file_replaces => ConflictPolicy::Rename,
+                policy => policy,
+            });
+        let saved = complete_file(incoming_file, file_size, &file_sha256, conflict).await;
+        if let Some((active, _)) = transfer.as_mut() {
+            match &saved {
+                Ok(_) if folder.is_none() => {
+                    active.finish(Outcome::Completed, Some(file_sha256.clone()), None)
+                }
+                Ok(_) => (),
+                Err((error, _)) => active.finish(Outcome::Failed, None, Some(error.clone())),
+            }
+        }
+        match &saved {
+            Ok(path) => host.report(
+                "file_received",
+                json!({
+                    "file_name": file_name,
+                    "file_size": file_size,
+                    "sha256": file_sha256,
+                    "path": path,
+                    "compression_ratio": file_compression.map(|_| compression::ratio(data_bytes, wire_bytes)),
+                    "peer": peer
+                }),
+            ),
+            // Files of a folder only finish here when interrupted, which fails the folder.
+            Err((error, resumable)) => host.report(
+                if folder.is_some() {
+                    "folder_failed"
+                } else {
+                    "file_failed"
+                },
+                json!({
+                    "folder_name": folder.as_ref().map(|f| &f.name),
+                    "file_name": file_name,
+                    "file_size": file_size,
+                    "error": error,
+                    "resumable": resumable,
+                    "peer": peer
+                }),
+            ),
+        }
+
+        let _ = ws_sender
+            .send(
+                P2pMessage::FileResult {
+                    success: saved.is_ok(),
+                }
+                .into(),
+            )
+            .await;
+        let _ = ws_sender.close().await;
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str) -> ManifestEntry {
+        ManifestEntry {
+            path: path.to_string(),
+            size: 0,
+            sha256: "0".repeat(64),
+            mode: None,
+        }
+    }
+
+    #[test]
+    fn safe_relative_path_keeps_plain_paths() {
+        assert_eq!(
+            safe_relative_path("photos/2024/beach.jpg"),
+            Some(["photos", "2024", "beach.jpg"].iter().collect())
+        );
+        assert_eq!(safe_relative_path("notes.txt"), Some("notes.txt".into()));
+    }
+
+    #[test]
+    fn safe_relative_path_refuses_traversal() {
+        for path in [
+            "..",
+            "../secret",
+            "photos/../../secret",
+            "photos/./beach.jpg",
+            ".",
+        ] {
+            assert_eq!(safe_relative_path(path), None, "{}", path);
+        }
+    }
+
+    #[test]
+    fn safe_relative_path_refuses_absolute_paths() {
+        for path in [
+            "/etc/passwd",
+            "C:/Windows",
+            r"C:\Windows",
+            r"\\server\share",
+        ] {
+            assert_eq!(safe_relative_path(path), None, "{}", path);
+        }
+    }
+
+    #[test]
+    fn safe_relative_path_refuses_names_that_would_change() {
+        for path in [
+            "",
+            "a//b",
+            "a/",
+            "CON/file",
+            "a/nul.txt",
+            "a/b:c",
+            "a/b\\c",
+            "a/b.",
+        ] {
+            assert_eq!(safe_relative_path(path), None, "{:?}", path);
+        }
+    }
+
+    #[test]
+    fn check_folder_accepts_a_valid_folder() {
+        assert_eq!(
+            check_folder("photos", &[entry("a.jpg"), entry("2024/b.jpg")]),
+            Ok(())
+        );
+        assert_eq!(check_folder("empty", &[]), Ok(()));
+    }
+
+    #[test]
+    fn check_folder_refuses_bad_names() {
+        for name in ["", "..", "a/b", "/photos", "CON"] {
+            assert!(check_folder(name, &[]).is_err(), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn check_folder_refuses_bad_entries() {
+        let with_hash = |sha256: String| ManifestEntry {
+            sha256,
+            ..entry("a.jpg")
+        };
+
+        for entries in [
+            vec![entry("../a.jpg")],
+            vec![entry("/etc/passwd")],
+            vec![entry("a/../../b")],
+            vec![with_hash("0".repeat(63))],
+            vec![with_hash("../".repeat(21) + "a")],
+            vec![entry("a.jpg"), entry("a.jpg")],
+        ] {
+            assert!(check_folder("photos", &entries).is_err(), "{:?}", entries);
+        }
+    }
+
+    #[test]
+    fn check_folder_limits_the_entries() {
+        let entries: Vec<_> = (0..=MAX_FOLDER_ENTRIES)
+            .map(|i| entry(&format!("{}.txt", i)))
+            .collect();
+        assert!(check_folder("photos", &entries).is_err());
+        assert_eq!(check_folder("photos", &entries[1..]), Ok(()));
+    }
+
+    #[test]
+    fn folder_record_is_named_after_the_manifest() {
+        let dir = Path::new("received");
+        let entries = [entry("a.jpg"), entry("b.jpg")];
+        let record = FolderRecord::path(dir, "photos", &entries);
+        assert_eq!(record, FolderRecord::path(dir, "photos", &entries));
+        assert!(record
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with("photos.")));
+
+        let grown = [entry("a.jpg"), entry("b.jpg"), entry("c.jpg")];
+        assert_ne!(record, FolderRecord::path(dir, "photos", &grown));
+        let changed = [
+            entry("a.jpg"),
+            ManifestEntry {
+                size: 1,
+                ..entry("b.jpg")
+            },
+        ];
+        assert_ne!(record, FolderRecord::path(dir, "photos", &changed));
+    }
+
+    #[tokio::test]
+    async fn folder_record_keeps_the_files_received() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = FolderRecord::path(temp.path(), "photos", &[entry("a.jpg")]);
+        assert!(FolderRecord::load(&path).await.is_none());
+
+        FolderRecord {
+            received: 3,
+            cancelled: true,
+        }
+        .save(&path)
+        .await
+        .unwrap();
+        let record = FolderRecord::load(&path).await.unwrap();
+        assert_eq!(record.received, 3);
+        assert!(record.cancelled);
+    }
+
+    #[tokio::test]
+    async fn is_received_checks_the_content() {
+        let temp = tempfile::tempdir().unwrap();
+        let a = ManifestEntry {
+            size: 3,
+            sha256: format!("{:x}", Sha256::digest(b"abc")),
+            ..entry("a.jpg")
+        };
+        assert!(!is_received(temp.path(), &a).await);
+
+        std::fs::write(temp.path().join("a.jpg"), "ab").unwrap();
+        assert!(!is_received(temp.path(), &a).await);
+        std::fs::write(temp.path().join("a.jpg"), "abd").unwrap();
+        assert!(!is_received(temp.path(), &a).await);
+        std::fs::write(temp.path().join("a.jpg"), "abc").unwrap();
+        assert!(is_received(temp.path(), &a).await);
+    }
+
+    #[tokio::test]
+    async fn only_a_cancelled_partial_file_is_marked() {
+        let temp = tempfile::tempdir().unwrap();
+        let sha256 = "0".repeat(64);
+        let incoming = IncomingFile::open(temp.path(), "a.txt", &sha256, 3)
+            .await
+            .unwrap();
+        incoming.suspend().await;
+        assert!(!IncomingFile::was_cancelled(temp.path(), "a.txt", &sha256).await);
+
+        let incoming = IncomingFile::open(temp.path(), "a.txt", &sha256, 3)
+            .await
+            .unwrap();
+        incoming.suspend_cancelled().await;
+        assert!(IncomingFile::was_cancelled(temp.path(), "a.txt", &sha256).await);
+
+        // Accepting it again resumes it.
+        let incoming = IncomingFile::open(temp.path(), "a.txt", &sha256, 3)
+            .await
+            .unwrap();
+        assert!(!IncomingFile::was_cancelled(temp.path(), "a.txt", &sha256).await);
+        incoming.discard().await;
+    }
+}
@@ -0,0 +1,164 @@
+// The rate limiter is only used by the app, the headless peer sends as fast as it can.
+#![cfg_attr(not(feature = "gui"), allow(dead_code))]
+
+#[cfg(feature = "gui")]
+use super::config::Config;
+#[cfg(feature = "gui")]
+use super::transfers::Transfers;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+#[cfg(feature = "gui")]
+use tauri::{AppHandle, Manager};
+
+/// The longest wait, so a huge chunk at a tiny rate still gives a time to wait until.
+const MAX_WAIT: Duration = Duration::from_secs(60 * 60);
+
+/// How often a transfer waiting on the limits checks them again, so raising or removing them
+/// doesn't leave it waiting out the old ones.
+pub const RECHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// A token bucket, refilled at a rate of bytes per second and holding at most a second's worth.
+struct TokenBucket {
+    /// Bytes per second, or 0 for no limit.
+    rate: u64,
+    /// What can be sent right away, negative when sending got ahead of the rate.
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            tokens: rate as f64,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    /// Takes tokens for the bytes about to be sent, returning how long to wait before sending more.
+    fn take(&mut self, rate: u64, bytes: u64) -> Duration {
+        // The rate can change at any time, so it is checked on every use.
+        if rate != self.rate {
+            *self = Self::new(rate);
+        }
+        if rate == 0 {
+            return Duration::ZERO;
+        }
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate as f64).min(rate as f64) - bytes as f64;
+        self.refilled_at = now;
+
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((-self.tokens / rate as f64).min(MAX_WAIT.as_secs_f64()))
+        }
+    }
+}
+
+/// The buckets limiting how fast files are sent, for all transfers together and for each one.
+pub struct RateLimiter {
+    global: Mutex<TokenBucket>,
+    transfers: Mutex<HashMap<String, TokenBucket>>,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self {
+            global: Mutex::new(TokenBucket::new(0)),
+            transfers: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl RateLimiter {
+    pub fn remove(&self, transfer_id: &str) {
+        self.transfers.lock().unwrap().remove(transfer_id);
+    }
+}
+
+/// Accounts for bytes a transfer is about to send, returning how long it should wait before
+/// sending more to stay within the configured limits.
+///
+/// The limits are read on every call, so changing them applies to transfers already running.
+/// Called with no bytes, it returns how much of the wait is left with the current limits.
+#[cfg(feature = "gui")]
+pub fn throttle(app: &AppHandle, transfer_id: &str, bytes: u64) -> Duration {
+    let config = app.state::<Config>().get();
+    let transfer_limit = app
+        .state::<Transfers>()
+        .rate_limit(transfer_id)
+        .unwrap_or(config.transfer_rate_limit);
+
+    let limiter = app.state::<RateLimiter>();
+    let global_wait = limiter
+        .global
+        .lock()
+        .unwrap()
+        .take(config.global_rate_limit.saturating_mul(1024), bytes);
+    let transfer_wait = limiter
+        .transfers
+        .lock()
+        .unwrap()
+        .entry(transfer_id.to_string())
+        .or_insert_with(|| TokenBucket::new(transfer_limit.saturating_mul(1024)))
+        .take(transfer_limit.saturating_mul(1024), bytes);
+
+    global_wait.max(transfer_wait)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_bucket_without_a_limit_never_waits() {
+        let mut bucket = TokenBucket::new(0);
+        assert_eq!(bucket.take(0, u64::MAX), Duration::ZERO);
+        assert_eq!(bucket.take(0, u64::MAX), Duration::ZERO);
+    }
+
+    #[test]
+    fn token_bucket_holds_a_second_then_waits() {
+        let mut bucket = TokenBucket::new(1000);
+        assert_eq!(bucket.take(1000, 1000), Duration::ZERO);
+
+        let wait = bucket.take(1000, 500);
+        assert!(wait > Duration::from_millis(450) && wait <= Duration::from_millis(500));
+
+        // Sending got ahead of the rate, so the next chunk waits for both.
+        let wait = bucket.take(1000, 500);
+        assert!(wait > Duration::from_millis(950) && wait <= Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn token_bucket_starts_over_when_the_rate_changes() {
+        let mut bucket = TokenBucket::new(1000);
+        assert!(bucket.take(1000, 5000) > Duration::ZERO);
+        assert_eq!(bucket.take(10_000, 5000), Duration::ZERO);
+        assert_eq!(bucket.take(0, 5000), Duration::ZERO);
+    }
+
+    #[test]
+    fn token_bucket_checks_the_wait_again_without_taking() {
+        let mut bucket = TokenBucket::new(1000);
+        let wait = bucket.take(1000, 2000);
+        let left = bucket.take(1000, 0);
+        assert!(left > Duration::ZERO && left <= wait);
+
+        // A raised limit ends the wait.
+        assert_eq!(bucket.take(100_000, 0), Duration::ZERO);
+    }
+
+    #[test]
+    fn token_bucket_does_not_overflow() {
+        let mut bucket = TokenBucket::new(1);
+        assert_eq!(bucket.take(1, u64::MAX), MAX_WAIT);
+
+        let mut bucket = TokenBucket::new(u64::MAX);
+        assert_eq!(bucket.take(u64::MAX, u64::MAX), Duration::ZERO);
+    }
+}
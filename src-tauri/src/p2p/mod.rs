@@ -0,0 +1,42 @@
+pub mod access;
+#[cfg(feature = "gui")]
+pub mod app;
+#[cfg(feature = "gui")]
+pub mod card;
+pub mod client;
+#[cfg(feature = "gui")]
+pub mod clipboard;
+pub mod compression;
+pub mod config;
+pub mod devices;
+pub mod discovery;
+pub mod history;
+pub mod host;
+pub mod incoming;
+pub mod limiter;
+pub mod peers;
+pub mod protocol;
+pub mod receive;
+pub mod secure;
+#[cfg(feature = "gui")]
+pub mod server;
+#[cfg(feature = "gui")]
+pub mod texts;
+pub mod transfers;
+#[cfg(feature = "gui")]
+pub mod web;
+
+#[cfg(feature = "gui")]
+use serde_json::json;
+#[cfg(feature = "gui")]
+use tauri::{AppHandle, Emitter, Manager};
+
+pub use protocol::P2P_PORT;
+
+/// Sends a P2P event to the main window.
+#[cfg(feature = "gui")]
+pub fn emit_p2p(app: &AppHandle, event: &str, data: serde_json::Value) {
+    if let Some(window) = app.get_webview_window("main") {
+        let _ = window.emit("e_p2p", json!({"event": event, "data": data}));
+    }
+}
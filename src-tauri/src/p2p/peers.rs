@@ -0,0 +1,79 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+
+/// Something the app asks a connection task to do.
+#[derive(Debug, Clone)]
+pub enum PeerCommand {
+    Disconnect,
+}
+
+/// A peer currently connected to the P2P server.
+#[derive(Serialize, Clone)]
+pub struct PeerInfo {
+    pub address: SocketAddr,
+    /// The paired device on the other end, once it has completed the handshake.
+    pub device_id: Option<String>,
+    pub device_name: Option<String>,
+    /// Unix timestamp (in milliseconds) of when the peer connected.
+    pub connected_at: u128,
+    #[serde(skip)]
+    commands: mpsc::UnboundedSender<PeerCommand>,
+}
+
+/// The registry of active peer connections, shared between connection tasks and commands.
+#[derive(Default)]
+pub struct Peers(Mutex<HashMap<SocketAddr, PeerInfo>>);
+
+impl Peers {
+    /// Registers a peer, returning the receiver its connection task should listen to for commands.
+    pub fn add(&self, address: SocketAddr) -> mpsc::UnboundedReceiver<PeerCommand> {
+        let (commands, receiver) = mpsc::unbounded_channel();
+        let connected_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or_default();
+
+        self.0.lock().unwrap().insert(
+            address,
+            PeerInfo {
+                address,
+                device_id: None,
+                device_name: None,
+                connected_at,
+                commands,
+            },
+        );
+
+        receiver
+    }
+
+    /// Records which paired device a peer is.
+    pub fn identify(&self, address: &SocketAddr, device_id: String, device_name: String) {
+        if let Some(peer) = self.0.lock().unwrap().get_mut(address) {
+            peer.device_id = Some(device_id);
+            peer.device_name = Some(device_name);
+        }
+    }
+
+    pub fn remove(&self, address: &SocketAddr) {
+        self.0.lock().unwrap().remove(address);
+    }
+
+    pub fn list(&self) -> Vec<PeerInfo> {
+        let mut peers: Vec<_> = self.0.lock().unwrap().values().cloned().collect();
+        peers.sort_by_key(|p| p.connected_at);
+        peers
+    }
+
+    /// Sends a command to a peer's connection task, returning false if the peer is not connected.
+    pub fn send(&self, address: &SocketAddr, command: PeerCommand) -> bool {
+        match self.0.lock().unwrap().get(address) {
+            Some(peer) => peer.commands.send(command).is_ok(),
+            None => false,
+        }
+    }
+}
@@ -0,0 +1,337 @@
+use super::compression::COMPRESSIONS;
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::{Message, Result};
+use tokio_tungstenite::{client_async, WebSocketStream};
+
+/// The port the P2P server listens on (unless configured otherwise).
+pub const P2P_PORT: u16 = 15446;
+
+/// The DNS-SD service type instances announce themselves under, with their device ID (`id`), name
+/// (`name`) and protocol version (`version`) in the TXT record.
+pub const SERVICE_TYPE: &str = "_isaac-p2p._tcp.local.";
+
+/// The version of the wire protocol, bumped on any incompatible change.
+pub const PROTOCOL_VERSION: u32 = 8;
+
+/// The capabilities this build supports.
+pub const CAPABILITIES: &[Capability] = &[Capability::File, Capability::Folder, Capability::Text];
+
+/// Something a peer is able to do over the connection.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    File,
+    Folder,
+    Text,
+    /// A capability from a newer version that this build does not know about.
+    #[serde(other)]
+    Unknown,
+}
+
+/// A compression for file data and large texts, negotiated during the handshake.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    Deflate,
+    #[serde(other)]
+    Unknown,
+}
+
+/// Something asked of a running transfer (by the app, or by the peer in a control frame).
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TransferCommand {
+    Pause,
+    Resume,
+    Cancel,
+}
+
+/// Why a peer refused or failed a message.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    UnsupportedVersion,
+    HandshakeRequired,
+    InvalidMessage,
+    UnexpectedMessage,
+    UnsupportedCapability,
+    /// The device has not been paired, so the connection is refused.
+    Unpaired,
+    AuthFailed,
+    /// The offer goes against the receiver's policy (too big, not enough space...).
+    Refused,
+    #[serde(other)]
+    Unknown,
+}
+
+/// A message sent as a JSON text frame between peers (file data is sent as binary frames).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum P2pMessage {
+    /// Sent by the connecting peer to start the handshake.
+    Hello {
+        version: u32,
+        capabilities: Vec<Capability>,
+        device_id: String,
+        device_name: String,
+        /// The compressions the peer supports, in order of preference.
+        #[serde(default)]
+        compressions: Vec<Compression>,
+        /// The port the peer's own server listens on, when it has one.
+        #[serde(default)]
+        port: Option<u16>,
+    },
+    /// Sent back to a trusted device, which must prove it knows the secret shared when pairing.
+    AuthChallenge {
+        device_id: String,
+        device_name: String,
+        nonce: String,
+    },
+    AuthResponse {
+        proof: String,
+    },
+    /// Sent instead of a hello to pair with the peer.
+    PairRequest {
+        version: u32,
+        device_id: String,
+        device_name: String,
+        nonce: String,
+        /// The one-time token from the peer's connection card, used up by the attempt.
+        #[serde(default)]
+        token: Option<String>,
+    },
+    /// Sent back to a pair request, the pairing code is derived from both nonces.
+    PairChallenge {
+        device_id: String,
+        device_name: String,
+        nonce: String,
+    },
+    /// Sent once the user has compared the pairing codes, with the shared secret when accepted.
+    PairResult {
+        accepted: bool,
+        #[serde(default)]
+        secret: String,
+    },
+    /// Sent instead of a hello to find out what the peer is, without being paired.
+    Probe,
+    /// Sent back to a probe.
+    Identity {
+        device_id: String,
+        device_name: String,
+        version: u32,
+    },
+    /// Sent back when the handshake succeeds, with the capabilities both peers share.
+    Welcome {
+        version: u32,
+        capabilities: Vec<Capability>,
+        /// The compression picked for the connection, if both peers support one.
+        #[serde(default)]
+        compression: Option<Compression>,
+    },
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
+    /// Asks the peer to accept a file, identified by the hex encoded SHA-256 of its content.
+    ///
+    /// When compressed, each binary frame starts with a byte telling whether it is compressed
+    /// (the size, offset and hash are always of the uncompressed content).
+    FileOffer {
+        name: String,
+        size: u64,
+        sha256: String,
+        #[serde(default)]
+        compression: Option<Compression>,
+    },
+    /// Asks the peer to accept a whole folder at once.
+    ///
+    /// Once accepted, each entry is sent in order as a file offer named after its path, which the
+    /// peer accepts without asking again.
+    FolderOffer {
+        name: String,
+        entries: Vec<ManifestEntry>,
+    },
+    /// Answers a file (or folder) offer, with the offset to send from when resuming a previous transfer.
+    FileAnswer {
+        accept: bool,
+        #[serde(default)]
+        offset: u64,
+    },
+    /// Pauses, resumes or cancels the file being sent, from either side.
+    TransferControl {
+        action: TransferCommand,
+    },
+    /// Sent once all the file data has (or has not) been written.
+    FileResult {
+        success: bool,
+    },
+    /// Text to show, or to copy when it comes from the peer's clipboard sync.
+    ///
+    /// A compressed text is base64 encoded.
+    Text {
+        text: String,
+        #[serde(default)]
+        clipboard: bool,
+        #[serde(default)]
+        compression: Option<Compression>,
+    },
+}
+
+/// A file in a folder offer.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ManifestEntry {
+    /// The path relative to the folder, with `/` separators.
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+    /// The unix permission bits, when the sender has them.
+    #[serde(default)]
+    pub mode: Option<u32>,
+}
+
+impl P2pMessage {
+    pub fn hello(device_id: String, device_name: String, port: Option<u16>) -> Self {
+        P2pMessage::Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: CAPABILITIES.to_vec(),
+            device_id,
+            device_name,
+            compressions: COMPRESSIONS.to_vec(),
+            port,
+        }
+    }
+
+    pub fn error(code: ErrorCode, message: impl Into<String>) -> Self {
+        P2pMessage::Error {
+            code,
+            message: message.into(),
+        }
+    }
+
+    /// Parses a text frame, returning `None` for frames that are not text.
+    pub fn from_ws(msg: &Message) -> Option<Result<Self, serde_json::Error>> {
+        match msg {
+            Message::Text(text) => Some(serde_json::from_str(text)),
+            _ => None,
+        }
+    }
+}
+
+impl From<P2pMessage> for Message {
+    fn from(msg: P2pMessage) -> Self {
+        Message::Text(serde_json::to_string(&msg).expect("P2P messages always serialize"))
+    }
+}
+
+/// What both peers agreed on during the handshake.
+pub struct Negotiated {
+    pub capabilities: Vec<Capability>,
+    pub compression: Option<Compression>,
+}
+
+/// Returns the capabilities shared between this build and a peer.
+pub fn shared_capabilities(peer_capabilities: &[Capability]) -> Vec<Capability> {
+    CAPABILITIES
+        .iter()
+        .filter(|c| peer_capabilities.contains(c))
+        .copied()
+        .collect()
+}
+
+/// Waits for the next protocol message, skipping pings and turning error frames into errors.
+pub async fn next_message<S>(ws_receiver: &mut S) -> Result<P2pMessage, String>
+where
+    S: Stream<Item = Result<Message>> + Unpin,
+{
+    match read_message(ws_receiver).await? {
+        P2pMessage::Error { message, .. } => Err(message),
+        parsed => Ok(parsed),
+    }
+}
+
+/// Waits for the next protocol message (error frames included), skipping pings.
+pub async fn read_message<S>(ws_receiver: &mut S) -> Result<P2pMessage, String>
+where
+    S: Stream<Item = Result<Message>> + Unpin,
+{
+    while let Some(msg) = ws_receiver.next().await {
+        let msg = msg.map_err(|e| e.to_string())?;
+        if let Message::Close(Some(frame)) = &msg {
+            return Err(format!("The peer closed the connection: {}", frame.reason));
+        }
+        if msg.is_close() {
+            break;
+        }
+
+        match P2pMessage::from_ws(&msg) {
+            None => continue,
+            Some(Ok(parsed)) => return Ok(parsed),
+            Some(Err(e)) => return Err(format!("Invalid message from the peer: {}", e)),
+        }
+    }
+
+    Err("The connection was closed by the peer.".to_string())
+}
+
+/// Adds the P2P port to an address that does not have one, putting IPv6 addresses in brackets.
+pub fn with_default_port(address: &str) -> String {
+    let ip = address.split_once('%').map_or(address, |(ip, _)| ip);
+    match ip.parse::<IpAddr>() {
+        Ok(IpAddr::V6(_)) => format!("[{}]:{}", address, P2P_PORT),
+        Ok(IpAddr::V4(_)) => format!("{}:{}", address, P2P_PORT),
+        Err(_) if address.contains(':') => address.to_string(),
+        Err(_) => format!("{}:{}", address, P2P_PORT),
+    }
+}
+
+/// Opens a WebSocket to the peer at the given `host:port`.
+///
+/// The TCP connection is made first, so link-local IPv6 addresses keep their scope
+/// (`[fe80::1%2]:15446`), which a URL can't carry.
+pub async fn connect_ws(address: &str) -> Result<WebSocketStream<TcpStream>, String> {
+    let stream = TcpStream::connect(address)
+        .await
+        .map_err(|e| e.to_string())?;
+    let url = match address.parse::<SocketAddr>() {
+        Ok(SocketAddr::V6(v6)) => format!("ws://[{}]:{}", v6.ip(), v6.port()),
+        _ => format!("ws://{}", address),
+    };
+
+    let (ws_stream, _) = client_async(url, stream).await.map_err(|e| e.to_string())?;
+    Ok(ws_stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn with_default_port_adds_the_port() {
+        assert_eq!(with_default_port("192.168.1.2"), "192.168.1.2:15446");
+        assert_eq!(with_default_port("laptop.local"), "laptop.local:15446");
+    }
+
+    #[test]
+    fn with_default_port_keeps_a_given_port() {
+        assert_eq!(with_default_port("192.168.1.2:80"), "192.168.1.2:80");
+        assert_eq!(with_default_port("laptop.local:80"), "laptop.local:80");
+        assert_eq!(with_default_port("[::1]:80"), "[::1]:80");
+        assert_eq!(with_default_port("[fe80::1%eth0]:80"), "[fe80::1%eth0]:80");
+    }
+
+    #[test]
+    fn with_default_port_brackets_ipv6() {
+        assert_eq!(with_default_port("::1"), "[::1]:15446");
+        assert_eq!(with_default_port("2001:db8::1"), "[2001:db8::1]:15446");
+    }
+
+    #[test]
+    fn with_default_port_keeps_the_ipv6_scope() {
+        assert_eq!(with_default_port("fe80::1%eth0"), "[fe80::1%eth0]:15446");
+        assert_eq!(with_default_port("fe80::1%2"), "[fe80::1%2]:15446");
+        assert!(with_default_port("fe80::1%2").parse::<SocketAddr>().is_ok());
+    }
+}
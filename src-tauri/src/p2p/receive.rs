@@ -0,0 +1,272 @@
+#[cfg(feature = "gui")]
+use super::config::Config;
+use super::config::P2pConfig;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+#[cfg(feature = "gui")]
+use std::path::PathBuf;
+#[cfg(feature = "gui")]
+use tauri::{AppHandle, Manager};
+
+/// The longest name a received file is saved as, leaving room for the partial file suffix within
+/// the 255 bytes most file systems allow.
+const MAX_NAME_LEN: usize = 200;
+
+/// How many numbered names are tried before giving up on finding a free one.
+const MAX_RENAMES: u32 = 1000;
+
+/// Names Windows reserves for devices, whatever the extension.
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// What to do when a received file (or folder) has the name of an existing one.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictPolicy {
+    /// Save it as `name (1).ext` (or the first free number).
+    #[default]
+    Rename,
+    Overwrite,
+    /// Tell the user when asking about it, accepting then overwrites.
+    Ask,
+}
+
+/// Returns the folder received files are saved to: the configured one, or the downloads folder.
+#[cfg(feature = "gui")]
+pub fn receive_dir(app: &AppHandle) -> std::io::Result<PathBuf> {
+    match app.state::<Config>().get().receive_dir {
+        Some(dir) if !dir.is_empty() => Ok(PathBuf::from(dir)),
+        _ => app
+            .path()
+            .download_dir()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::NotFound, e.to_string())),
+    }
+}
+
+/// Turns a name announced by a peer into one safe to save a file as on any platform, or `None`
+/// if nothing is left of it.
+pub fn sanitize_name(name: &str) -> Option<String> {
+    let mut safe: String = name
+        .chars()
+        .map(|c| {
+            if c.is_control() || r#"<>:"/\|?*"#.contains(c) {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect();
+
+    // Windows drops trailing dots and spaces, which also gets rid of `.` and `..`.
+    safe.truncate(safe.trim_end_matches(['.', ' ']).len());
+    if safe.is_empty() {
+        return None;
+    }
+
+    let stem = safe.split('.').next().unwrap_or_default().trim_end();
+    if RESERVED_NAMES.iter().any(|r| stem.eq_ignore_ascii_case(r)) {
+        safe.insert(0, '_');
+    }
+
+    // Shorten the stem of long names, keeping a (short) extension.
+    if safe.len() > MAX_NAME_LEN {
+        let (stem, extension) = match safe.rfind('.') {
+            Some(i) if i > 0 && safe.len() - i <= 16 => safe.split_at(i),
+            _ => (safe.as_str(), ""),
+        };
+        let mut stem = stem.to_string();
+        while stem.len() + extension.len() > MAX_NAME_LEN {
+            stem.pop();
+        }
+        safe = stem + extension;
+    }
+
+    Some(safe)
+}
+
+/// Picks the name to save a file (or folder) as in the given folder, returning it and whether it
+/// replaces an existing one, or `None` when no free name was found.
+///
+/// A name holding part of the same transfer (`resumes`) is taken again, so the transfer resumes.
+pub fn target_name(
+    dir: &Path,
+    name: &str,
+    policy: ConflictPolicy,
+    resumes: impl Fn(&str) -> bool,
+) -> Option<(String, bool)> {
+    let free = |candidate: &str| !dir.join(candidate).exists() || resumes(candidate);
+    if free(name) {
+        return Some((name.to_string(), false));
+    }
+    if policy != ConflictPolicy::Rename {
+        return Some((name.to_string(), true));
+    }
+
+    let (stem, extension) = match name.rfind('.') {
+        Some(i) if i > 0 => name.split_at(i),
+        _ => (name, ""),
+    };
+    (1..=MAX_RENAMES)
+        .map(|n| format!("{} ({}){}", stem, n, extension))
+        .find(|candidate| free(candidate))
+        .map(|candidate| (candidate, false))
+}
+
+/// Checks an offer of `size` bytes to save in the given folder, of which `needed` are not on disk
+/// yet (from an earlier attempt), returning why it is refused.
+pub fn check_offer(config: &P2pConfig, dir: &Path, size: u64, needed: u64) -> Result<(), String> {
+    if config.max_receive_size > 0 && size > config.max_receive_size.saturating_mul(1024 * 1024) {
+        return Err(format!(
+            "Files over {} MiB are not accepted.",
+            config.max_receive_size
+        ));
+    }
+
+    // The folder itself may not exist yet, its closest parent that does is on the same disk.
+    let existing = dir
+        .ancestors()
+        .find(|path| path.exists())
+        .ok_or("The receive folder does not exist.")?;
+    let available = fs4::available_space(existing).map_err(|e| e.to_string())?;
+    if needed > available {
+        return Err("There is not enough disk space.".to_string());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitize_name_keeps_plain_names() {
+        assert_eq!(sanitize_name("report.pdf").as_deref(), Some("report.pdf"));
+        assert_eq!(sanitize_name(".bashrc").as_deref(), Some(".bashrc"));
+        assert_eq!(
+            sanitize_name("été 2024.jpg").as_deref(),
+            Some("été 2024.jpg")
+        );
+    }
+
+    #[test]
+    fn sanitize_name_replaces_separators_and_special_characters() {
+        assert_eq!(sanitize_name("../secret").as_deref(), Some(".._secret"));
+        assert_eq!(sanitize_name("/etc/passwd").as_deref(), Some("_etc_passwd"));
+        assert_eq!(sanitize_name(r"C:\Windows").as_deref(), Some("C__Windows"));
+        assert_eq!(
+            sanitize_name("a<b>c|d?e*\"f").as_deref(),
+            Some("a_b_c_d_e__f")
+        );
+        assert_eq!(
+            sanitize_name("line\nbreak\0").as_deref(),
+            Some("line_break_")
+        );
+    }
+
+    #[test]
+    fn sanitize_name_drops_trailing_dots_and_spaces() {
+        assert_eq!(sanitize_name("notes. . ").as_deref(), Some("notes"));
+        for name in ["", ".", "..", " ", ". ."] {
+            assert_eq!(sanitize_name(name), None, "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn sanitize_name_escapes_reserved_names() {
+        assert_eq!(sanitize_name("CON").as_deref(), Some("_CON"));
+        assert_eq!(sanitize_name("nul.txt").as_deref(), Some("_nul.txt"));
+        assert_eq!(
+            sanitize_name("Com1 .tar.gz").as_deref(),
+            Some("_Com1 .tar.gz")
+        );
+        assert_eq!(sanitize_name("CONSOLE.txt").as_deref(), Some("CONSOLE.txt"));
+        assert_eq!(sanitize_name("LPT10").as_deref(), Some("LPT10"));
+    }
+
+    #[test]
+    fn sanitize_name_shortens_long_names() {
+        let long = format!("{}.tar.gz", "a".repeat(300));
+        let safe = sanitize_name(&long).unwrap();
+        assert_eq!(safe.len(), MAX_NAME_LEN);
+        assert!(safe.ends_with("a.gz"));
+
+        // Multi-byte characters are never cut in half.
+        let safe = sanitize_name(&"é".repeat(150)).unwrap();
+        assert!(safe.len() <= MAX_NAME_LEN);
+        assert!(safe.chars().all(|c| c == 'é'));
+
+        // A long "extension" is not one.
+        let safe = sanitize_name(&format!("a.{}", "b".repeat(300))).unwrap();
+        assert_eq!(safe.len(), MAX_NAME_LEN);
+        assert!(safe.starts_with("a.b"));
+    }
+
+    #[test]
+    fn target_name_takes_free_names() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        assert_eq!(
+            target_name(dir, "a.txt", ConflictPolicy::Rename, |_| false),
+            Some(("a.txt".to_string(), false))
+        );
+    }
+
+    #[test]
+    fn target_name_renames_taken_names() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        std::fs::write(dir.join("a.txt"), "").unwrap();
+        std::fs::write(dir.join("a (1).txt"), "").unwrap();
+        std::fs::write(dir.join("README"), "").unwrap();
+        std::fs::write(dir.join(".env"), "").unwrap();
+
+        assert_eq!(
+            target_name(dir, "a.txt", ConflictPolicy::Rename, |_| false),
+            Some(("a (2).txt".to_string(), false))
+        );
+        assert_eq!(
+            target_name(dir, "README", ConflictPolicy::Rename, |_| false),
+            Some(("README (1)".to_string(), false))
+        );
+        assert_eq!(
+            target_name(dir, ".env", ConflictPolicy::Rename, |_| false),
+            Some((".env (1)".to_string(), false))
+        );
+    }
+
+    #[test]
+    fn target_name_replaces_or_resumes() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        std::fs::write(dir.join("a.txt"), "").unwrap();
+
+        for policy in [ConflictPolicy::Overwrite, ConflictPolicy::Ask] {
+            assert_eq!(
+                target_name(dir, "a.txt", policy, |_| false),
+                Some(("a.txt".to_string(), true))
+            );
+        }
+        assert_eq!(
+            target_name(dir, "a.txt", ConflictPolicy::Rename, |name| name == "a.txt"),
+            Some(("a.txt".to_string(), false))
+        );
+    }
+
+    #[test]
+    fn target_name_gives_up_after_too_many_renames() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        std::fs::write(dir.join("a.txt"), "").unwrap();
+        for n in 1..=MAX_RENAMES {
+            std::fs::write(dir.join(format!("a ({}).txt", n)), "").unwrap();
+        }
+
+        assert_eq!(
+            target_name(dir, "a.txt", ConflictPolicy::Rename, |_| false),
+            None
+        );
+    }
+}
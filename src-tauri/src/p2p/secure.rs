@@ -0,0 +1,215 @@
+// The encrypted halves have to keep tungstenite's (large) error type to stand in for the plain ones.
+#![allow(clippy::result_large_err)]
+
+use futures::{future, Sink, SinkExt, Stream, StreamExt};
+use snow::{params::NoiseParams, Builder, HandshakeState, StatelessTransportState};
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio_tungstenite::tungstenite::{Error, Message, Result};
+
+/// The Noise pattern used to encrypt connections (both sides authenticate with their device keys).
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+
+/// The largest Noise message, and how much of it is left for the payload after the auth tag.
+const MAX_NOISE_MESSAGE: usize = 65535;
+const MAX_PLAINTEXT: usize = MAX_NOISE_MESSAGE - 16;
+
+/// The first byte of a decrypted frame, telling which kind of WebSocket message it was.
+const TEXT_FRAME: u8 = 0;
+const BINARY_FRAME: u8 = 1;
+
+/// The sending half of an encrypted connection.
+pub type SecureSender = Pin<Box<dyn Sink<Message, Error = Error> + Send>>;
+
+/// The receiving half of an encrypted connection.
+pub type SecureReceiver = Pin<Box<dyn Stream<Item = Result<Message>> + Send>>;
+
+/// What the Noise handshake established about the other side.
+pub struct Session {
+    /// The peer's public device key (hex encoded), pinned when pairing.
+    pub remote_key: String,
+    /// Unique to this connection (hex encoded), so both sides can tell they share the same one.
+    pub session_id: String,
+}
+
+/// Generates a new device keypair, returning the (private, public) keys hex encoded.
+pub fn generate_keypair() -> (String, String) {
+    let params: NoiseParams = NOISE_PARAMS.parse().expect("valid Noise params");
+    let keypair = Builder::new(params)
+        .generate_keypair()
+        .expect("the default resolver generates keypairs");
+
+    (hex::encode(keypair.private), hex::encode(keypair.public))
+}
+
+/// Runs the Noise handshake as the side that opened the connection.
+pub async fn initiate<S, R>(
+    private_key: &str,
+    ws_sender: S,
+    ws_receiver: R,
+) -> Result<(SecureSender, SecureReceiver, Session), String>
+where
+    S: Sink<Message, Error = Error> + Unpin + Send + 'static,
+    R: Stream<Item = Result<Message>> + Unpin + Send + 'static,
+{
+    let noise = build(private_key, true)?;
+    handshake(noise, true, ws_sender, ws_receiver).await
+}
+
+/// Runs the Noise handshake as the side that accepted the connection.
+pub async fn respond<S, R>(
+    private_key: &str,
+    ws_sender: S,
+    ws_receiver: R,
+) -> Result<(SecureSender, SecureReceiver, Session), String>
+where
+    S: Sink<Message, Error = Error> + Unpin + Send + 'static,
+    R: Stream<Item = Result<Message>> + Unpin + Send + 'static,
+{
+    let noise = build(private_key, false)?;
+    handshake(noise, false, ws_sender, ws_receiver).await
+}
+
+fn build(private_key: &str, initiator: bool) -> Result<HandshakeState, String> {
+    let params: NoiseParams = NOISE_PARAMS
+        .parse()
+        .map_err(|e: snow::Error| e.to_string())?;
+    let private_key = hex::decode(private_key).map_err(|e| e.to_string())?;
+    let builder = Builder::new(params).local_private_key(&private_key);
+    let noise = if initiator {
+        builder.build_initiator()
+    } else {
+        builder.build_responder()
+    };
+
+    noise.map_err(|e| e.to_string())
+}
+
+async fn handshake<S, R>(
+    mut noise: HandshakeState,
+    initiator: bool,
+    mut ws_sender: S,
+    mut ws_receiver: R,
+) -> Result<(SecureSender, SecureReceiver, Session), String>
+where
+    S: Sink<Message, Error = Error> + Unpin + Send + 'static,
+    R: Stream<Item = Result<Message>> + Unpin + Send + 'static,
+{
+    let mut buffer = vec![0; MAX_NOISE_MESSAGE];
+
+    // The initiator writes first, then both sides take turns until the pattern is done.
+    let mut writing = initiator;
+    while !noise.is_handshake_finished() {
+        if writing {
+            let len = noise
+                .write_message(&[], &mut buffer)
+                .map_err(|e| e.to_string())?;
+            ws_sender
+                .send(Message::Binary(buffer[..len].to_vec()))
+                .await
+                .map_err(|e| e.to_string())?;
+        } else {
+            let msg = loop {
+                match ws_receiver.next().await {
+                    Some(Ok(Message::Binary(data))) => break data,
+                    Some(Ok(Message::Ping(_) | Message::Pong(_))) => continue,
+                    Some(Ok(_)) | None => {
+                        return Err("The peer did not encrypt the connection.".to_string())
+                    }
+                    Some(Err(e)) => return Err(e.to_string()),
+                }
+            };
+            noise
+                .read_message(&msg, &mut buffer)
+                .map_err(|e| e.to_string())?;
+        }
+
+        writing = !writing;
+    }
+
+    let session = Session {
+        remote_key: noise
+            .get_remote_static()
+            .map(hex::encode)
+            .ok_or("The peer did not send its key.")?,
+        session_id: hex::encode(noise.get_handshake_hash()),
+    };
+    let noise = Arc::new(
+        noise
+            .into_stateless_transport_mode()
+            .map_err(|e| e.to_string())?,
+    );
+
+    // Each direction keeps its own nonce, so the halves can be used independently.
+    let noise_sender = noise.clone();
+    let mut send_nonce = 0;
+    let ws_sender = ws_sender
+        .with(move |msg: Message| future::ready(encrypt(&noise_sender, &mut send_nonce, msg)));
+
+    let mut receive_nonce = 0;
+    let ws_receiver =
+        ws_receiver.map(move |msg| msg.and_then(|msg| decrypt(&noise, &mut receive_nonce, msg)));
+
+    Ok((Box::pin(ws_sender), Box::pin(ws_receiver), session))
+}
+
+/// Encrypts a text or binary message into a binary frame (control frames are sent as is).
+fn encrypt(noise: &StatelessTransportState, nonce: &mut u64, msg: Message) -> Result<Message> {
+    let (kind, data) = match msg {
+        Message::Text(text) => (TEXT_FRAME, text.into_bytes()),
+        Message::Binary(data) => (BINARY_FRAME, data),
+        other => return Ok(other),
+    };
+
+    let mut plaintext = Vec::with_capacity(data.len() + 1);
+    plaintext.push(kind);
+    plaintext.extend_from_slice(&data);
+
+    // Messages bigger than a single Noise message are split into several.
+    let mut frame =
+        Vec::with_capacity(plaintext.len() + (plaintext.len() / MAX_PLAINTEXT + 1) * 16);
+    let mut buffer = vec![0; MAX_NOISE_MESSAGE];
+    for segment in plaintext.chunks(MAX_PLAINTEXT) {
+        let len = noise
+            .write_message(*nonce, segment, &mut buffer)
+            .map_err(invalid_data)?;
+        *nonce += 1;
+        frame.extend_from_slice(&buffer[..len]);
+    }
+
+    Ok(Message::Binary(frame))
+}
+
+/// Decrypts a binary frame back into the text or binary message it was.
+fn decrypt(noise: &StatelessTransportState, nonce: &mut u64, msg: Message) -> Result<Message> {
+    let data = match msg {
+        Message::Binary(data) => data,
+        Message::Text(_) => return Err(invalid_data("Received an unencrypted message.")),
+        other => return Ok(other),
+    };
+
+    let mut plaintext = Vec::with_capacity(data.len());
+    let mut buffer = vec![0; MAX_NOISE_MESSAGE];
+    for segment in data.chunks(MAX_NOISE_MESSAGE) {
+        let len = noise
+            .read_message(*nonce, segment, &mut buffer)
+            .map_err(invalid_data)?;
+        *nonce += 1;
+        plaintext.extend_from_slice(&buffer[..len]);
+    }
+
+    match plaintext.split_first() {
+        Some((&TEXT_FRAME, text)) => String::from_utf8(text.to_vec())
+            .map(Message::Text)
+            .map_err(|_| Error::Utf8),
+        Some((&BINARY_FRAME, data)) => Ok(Message::Binary(data.to_vec())),
+        _ => Err(invalid_data("Received an unknown frame.")),
+    }
+}
+
+fn invalid_data(e: impl ToString) -> Error {
+    Error::Io(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        e.to_string(),
+    ))
+}
@@ -0,0 +1,189 @@
+use super::access::{AccessControl, Admission};
+use super::config::{Config, P2pConfig};
+use super::discovery;
+use super::emit_p2p;
+use super::incoming::{self, listen_on, ACCEPT_RETRY_DELAY};
+use super::peers::Peers;
+use super::web;
+use serde::Serialize;
+use serde_json::json;
+use std::net::{IpAddr, SocketAddr, SocketAddrV6};
+use std::sync::Mutex;
+use tauri::{AppHandle, Manager};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::tungstenite::Error;
+
+/// Whether the P2P server is listening, and where.
+#[derive(Serialize, Clone, Debug, Default)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum ListenStatus {
+    #[default]
+    Starting,
+    Listening {
+        address: SocketAddr,
+        /// Whether the configured port was taken, so a random one is used instead.
+        fallback: bool,
+    },
+    Failed {
+        error: String,
+    },
+}
+
+/// The server's current listen status, for the UI to ask about.
+#[derive(Default)]
+pub struct ListenState(Mutex<ListenStatus>);
+
+impl ListenState {
+    pub fn get(&self) -> ListenStatus {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+/// Records the listen status and lets the UI know.
+fn set_status(app: &AppHandle, status: ListenStatus) {
+    *app.state::<ListenState>().0.lock().unwrap() = status.clone();
+    emit_p2p(app, "listen_status", json!(status));
+}
+
+/// Listens for incoming peer connections on the configured address and port.
+pub async fn listen(app: AppHandle) {
+    let config = app.state::<Config>().get();
+    let (listener, fallback) = match bind(&config) {
+        Ok(bound) => bound,
+        Err(error) => {
+            println!("Can't listen: {}", error);
+            set_status(&app, ListenStatus::Failed { error });
+            return;
+        }
+    };
+    let address = match listener.local_addr() {
+        Ok(address) => address,
+        Err(e) => {
+            set_status(
+                &app,
+                ListenStatus::Failed {
+                    error: e.to_string(),
+                },
+            );
+            return;
+        }
+    };
+    println!("Listening on: {}", address);
+    set_status(&app, ListenStatus::Listening { address, fallback });
+    discovery::announce(&app, address);
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            // Failing to accept one connection (like when out of file descriptors) is no reason to
+            // stop listening, just wait a bit before trying again.
+            Err(e) => {
+                println!("Failed accepting a connection: {}", e);
+                tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                continue;
+            }
+        };
+        // IPv4 peers of the dual-stack listener come as IPv4-mapped IPv6 addresses.
+        let peer = SocketAddr::new(peer.ip().to_canonical(), peer.port());
+        println!("Peer address: {}", peer);
+
+        // Rejected peers are dropped before the WebSocket handshake, so they cost next to nothing.
+        let config = app.state::<Config>().get();
+        let admission = match app.state::<AccessControl>().admit(peer.ip(), &config) {
+            Ok(admission) => admission,
+            Err(reason) => {
+                println!("Rejected peer {}: {}", peer, reason);
+                continue;
+            }
+        };
+
+        // Each peer gets its own task so one connection never holds up the others.
+        let app = app.clone();
+        tauri::async_runtime::spawn(async move {
+            accept_connection(peer, stream, &app, admission).await;
+        });
+    }
+}
+
+/// Binds the configured address, returning the listener and whether it fell back to a random port.
+fn bind(config: &P2pConfig) -> Result<(TcpListener, bool), String> {
+    let address = match config.listen_interface.as_deref().filter(|n| !n.is_empty()) {
+        Some(name) => interface_address(name, config.listen_port)?,
+        None => config
+            .listen_address
+            .parse::<IpAddr>()
+            .map(|ip| SocketAddr::new(ip, config.listen_port))
+            .map_err(|_| format!("Invalid listen address: {}", config.listen_address))?,
+    };
+
+    match listen_on(address) {
+        Ok(listener) => Ok((listener, false)),
+        Err(e) if config.random_port_fallback && address.port() != 0 => {
+            println!(
+                "Can't listen on port {} ({}), using a random one.",
+                address.port(),
+                e
+            );
+            let mut random = address;
+            random.set_port(0);
+            listen_on(random)
+                .map(|listener| (listener, true))
+                .map_err(|e| format!("Can't listen on {}: {}", address.ip(), e))
+        }
+        Err(e) => Err(format!("Can't listen on {}: {}", address, e)),
+    }
+}
+
+/// Finds the address of the network interface with the given name: its first IPv4 address, or
+/// else its first IPv6 one (global before link-local, which needs the interface as its scope).
+fn interface_address(name: &str, port: u16) -> Result<SocketAddr, String> {
+    let interface = netdev::get_interfaces()
+        .into_iter()
+        .find(|i| i.name == name || i.friendly_name.as_deref() == Some(name))
+        .ok_or_else(|| format!("No network interface named {}.", name))?;
+
+    if let Some(net) = interface.ipv4.first() {
+        return Ok(SocketAddr::new(IpAddr::V4(net.addr), port));
+    }
+
+    let mut ipv6: Vec<_> = interface.ipv6.iter().map(|net| net.addr).collect();
+    ipv6.sort_by_key(|ip| ip.is_unicast_link_local());
+    ipv6.first()
+        .map(|ip| {
+            let scope = if ip.is_unicast_link_local() {
+                interface.index
+            } else {
+                0
+            };
+            SocketAddr::V6(SocketAddrV6::new(*ip, port, 0, scope))
+        })
+        .ok_or_else(|| format!("The {} interface has no IP address.", name))
+}
+
+async fn accept_connection(
+    peer: SocketAddr,
+    stream: TcpStream,
+    app: &AppHandle,
+    _admission: Admission,
+) {
+    // Browsers get the web page (if it is turned on), only WebSocket upgrades come from peers.
+    if !web::is_websocket(&stream).await {
+        if app.state::<Config>().get().web_page {
+            web::serve(peer, stream, app).await;
+        }
+        return;
+    }
+
+    let commands = app.state::<Peers>().add(peer);
+    emit_p2p(app, "peer_connected", json!({ "peer": peer }));
+
+    if let Err(e) = incoming::handle_connection(peer, stream, app, commands).await {
+        match e {
+            Error::ConnectionClosed | Error::Protocol(_) | Error::Utf8 => (),
+            err => println!("Error processing connection: {}", err),
+        }
+    }
+
+    app.state::<Peers>().remove(&peer);
+    emit_p2p(app, "peer_disconnected", json!({ "peer": peer }));
+}
@@ -0,0 +1,78 @@
+use super::emit_p2p;
+use super::peers::Peers;
+use serde_json::json;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use tauri::{AppHandle, Manager};
+use tauri_plugin_notification::NotificationExt;
+use tauri_plugin_shell::ShellExt;
+
+/// The last text a peer sent, which can be copied (or its link opened) from the tray.
+#[derive(Default)]
+pub struct ReceivedText(Mutex<Option<String>>);
+
+impl ReceivedText {
+    /// Returns the last text received and the first link in it.
+    pub fn get(&self) -> Option<(String, Option<String>)> {
+        let text = self.0.lock().unwrap().clone()?;
+        let url = first_url(&text).map(str::to_string);
+        Some((text, url))
+    }
+}
+
+/// Shows a text a peer sent in a system notification (the window is usually hidden in the tray)
+/// and keeps it for the tray items.
+///
+/// The notification has no copy or open buttons: the notification plugin only supports actions on
+/// mobile, and the app only runs on desktop, so the tray items are how the text is acted on.
+pub fn receive(app: &AppHandle, peer: SocketAddr, text: String) {
+    let url = first_url(&text);
+    emit_p2p(
+        app,
+        "text_received",
+        json!({ "text": text, "url": url, "peer": peer }),
+    );
+
+    let from = app
+        .state::<Peers>()
+        .list()
+        .into_iter()
+        .find(|p| p.address == peer)
+        .and_then(|p| p.device_name)
+        .unwrap_or_else(|| peer.to_string());
+    if let Err(e) = app
+        .notification()
+        .builder()
+        .title(format!("Text from {}", from))
+        .body(&text)
+        .show()
+    {
+        println!("Failed showing the received text: {}", e);
+    }
+
+    *app.state::<ReceivedText>().0.lock().unwrap() = Some(text);
+    crate::update_tray(app);
+}
+
+/// Copies the last text received.
+pub fn copy(app: &AppHandle) {
+    if let Some((text, _)) = app.state::<ReceivedText>().get() {
+        crate::copy_text(text);
+    }
+}
+
+/// Opens the link in the last text received.
+pub fn open_link(app: &AppHandle) {
+    if let Some((_, Some(url))) = app.state::<ReceivedText>().get() {
+        if let Err(e) = app.shell().open(url, None) {
+            println!("Failed opening the received link: {}", e);
+        }
+    }
+}
+
+/// Returns the first web link in a text, so the user can open it.
+fn first_url(text: &str) -> Option<&str> {
+    text.split_whitespace()
+        .find(|word| word.starts_with("http://") || word.starts_with("https://"))
+        .map(|url| url.trim_end_matches(['.', ',', ';', ':', '!', '?', ')', '\'', '"']))
+}
@@ -0,0 +1,238 @@
+use super::devices::random_hex;
+#[cfg(feature = "gui")]
+use super::emit_p2p;
+#[cfg(feature = "gui")]
+use super::history::{self, Outcome};
+#[cfg(feature = "gui")]
+use super::limiter::RateLimiter;
+pub use super::protocol::TransferCommand;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "gui")]
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+#[cfg(feature = "gui")]
+use tauri::{AppHandle, Manager};
+use tokio::sync::mpsc;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Send,
+    Receive,
+}
+
+/// What happens to the partially received file when a transfer is cancelled.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum PartialPolicy {
+    /// Delete it, the transfer won't be resumed.
+    #[default]
+    Delete,
+    /// Keep it, so offering the same content again resumes from there.
+    Keep,
+}
+
+/// A file (or folder) currently being sent or received.
+#[derive(Serialize, Clone)]
+pub struct TransferInfo {
+    pub id: String,
+    pub direction: Direction,
+    pub name: String,
+    pub size: u64,
+    pub peer: String,
+    pub paused: bool,
+    /// The most KiB per second this transfer sends, overriding the configured limit.
+    pub rate_limit: Option<u64>,
+    /// Unix timestamp (in milliseconds) of when the transfer started.
+    pub started_at: u128,
+    /// How many times smaller the data was on the wire so far, when compressed.
+    pub compression_ratio: Option<f64>,
+    #[serde(skip)]
+    commands: mpsc::UnboundedSender<TransferCommand>,
+}
+
+/// The registry of active transfers, shared between transfer tasks, commands and the tray.
+#[derive(Default)]
+pub struct Transfers {
+    transfers: Mutex<HashMap<String, TransferInfo>>,
+}
+
+impl Transfers {
+    /// Registers a transfer, returning its ID and the receiver the transfer should listen to for
+    /// commands.
+    pub fn add(
+        &self,
+        direction: Direction,
+        name: &str,
+        size: u64,
+        peer: &str,
+    ) -> (String, mpsc::UnboundedReceiver<TransferCommand>) {
+        let (commands, receiver) = mpsc::unbounded_channel();
+        let id = random_hex(8);
+        let started_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or_default();
+
+        self.transfers.lock().unwrap().insert(
+            id.clone(),
+            TransferInfo {
+                id: id.clone(),
+                direction,
+                name: name.to_string(),
+                size,
+                peer: peer.to_string(),
+                paused: false,
+                rate_limit: None,
+                started_at,
+                compression_ratio: None,
+                commands,
+            },
+        );
+
+        (id, receiver)
+    }
+
+    /// Records whether a transfer is paused (by either side).
+    pub fn set_paused(&self, id: &str, paused: bool) {
+        if let Some(transfer) = self.transfers.lock().unwrap().get_mut(id) {
+            transfer.paused = paused;
+        }
+    }
+
+    /// Sets (or clears) a transfer's own rate limit, returning false if it is not running.
+    pub fn set_rate_limit(&self, id: &str, rate_limit: Option<u64>) -> bool {
+        match self.transfers.lock().unwrap().get_mut(id) {
+            Some(transfer) => {
+                transfer.rate_limit = rate_limit;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn set_compression_ratio(&self, id: &str, ratio: f64) {
+        if let Some(transfer) = self.transfers.lock().unwrap().get_mut(id) {
+            transfer.compression_ratio = Some(ratio);
+        }
+    }
+
+    pub fn rate_limit(&self, id: &str) -> Option<u64> {
+        self.transfers.lock().unwrap().get(id)?.rate_limit
+    }
+
+    pub fn remove(&self, id: &str) -> Option<TransferInfo> {
+        self.transfers.lock().unwrap().remove(id)
+    }
+
+    pub fn list(&self) -> Vec<TransferInfo> {
+        let mut transfers: Vec<_> = self.transfers.lock().unwrap().values().cloned().collect();
+        transfers.sort_by_key(|t| t.started_at);
+        transfers
+    }
+
+    /// Sends a command to a transfer, returning false if it is not running.
+    pub fn send(&self, id: &str, command: TransferCommand) -> bool {
+        match self.transfers.lock().unwrap().get(id) {
+            Some(transfer) => transfer.commands.send(command).is_ok(),
+            None => false,
+        }
+    }
+}
+
+/// Keeps a transfer registered while it runs, removing it (and recording it in the history) when
+/// dropped, however the transfer ends.
+#[cfg(feature = "gui")]
+pub struct ActiveTransfer {
+    app: AppHandle,
+    pub id: String,
+    outcome: Outcome,
+    sha256: Option<String>,
+    error: Option<String>,
+}
+
+#[cfg(feature = "gui")]
+impl ActiveTransfer {
+    /// Records how the transfer ended (it goes in the history as interrupted otherwise).
+    pub fn finish(&mut self, outcome: Outcome, sha256: Option<String>, error: Option<String>) {
+        self.outcome = outcome;
+        self.sha256 = sha256;
+        self.error = error;
+    }
+}
+
+#[cfg(feature = "gui")]
+impl Drop for ActiveTransfer {
+    fn drop(&mut self) {
+        if let Some(info) = self.app.state::<Transfers>().remove(&self.id) {
+            history::record(
+                &self.app,
+                &info,
+                self.outcome,
+                self.sha256.take(),
+                self.error.take(),
+            );
+        }
+        self.app.state::<RateLimiter>().remove(&self.id);
+        emit_p2p(
+            &self.app,
+            "transfer_ended",
+            json!({ "transfer_id": self.id }),
+        );
+        crate::update_tray(&self.app);
+    }
+}
+
+/// Registers a new transfer, returning it and the receiver to listen to for commands.
+#[cfg(feature = "gui")]
+pub fn start(
+    app: &AppHandle,
+    direction: Direction,
+    name: &str,
+    size: u64,
+    peer: &str,
+) -> (ActiveTransfer, mpsc::UnboundedReceiver<TransferCommand>) {
+    let transfers = app.state::<Transfers>();
+    let (id, commands) = transfers.add(direction, name, size, peer);
+    emit_p2p(
+        app,
+        "transfer_started",
+        json!({
+            "transfer_id": id,
+            "direction": direction,
+            "name": name,
+            "size": size,
+            "peer": peer
+        }),
+    );
+    crate::update_tray(app);
+
+    (
+        ActiveTransfer {
+            app: app.clone(),
+            id,
+            outcome: Outcome::Failed,
+            sha256: None,
+            error: Some("The transfer was interrupted.".to_string()),
+        },
+        commands,
+    )
+}
+
+/// Records that a transfer was paused or resumed (by either side) and lets the UI know.
+#[cfg(feature = "gui")]
+pub fn report_paused(app: &AppHandle, id: &str, paused: bool, by_peer: bool) {
+    app.state::<Transfers>().set_paused(id, paused);
+    emit_p2p(
+        app,
+        if paused {
+            "transfer_paused"
+        } else {
+            "transfer_resumed"
+        },
+        json!({"transfer_id": id, "by_peer": by_peer}),
+    );
+    crate::update_tray(app);
+}
@@ -0,0 +1,695 @@
+use super::app::listen_answer;
+use super::card;
+use super::config::Config;
+use super::devices::random_hex;
+use super::emit_p2p;
+use super::history::Outcome;
+use super::incoming::ASK_FILE_TIMEOUT;
+use super::limiter;
+use super::receive::{self, receive_dir, ConflictPolicy};
+use super::transfers::{self, ActiveTransfer, Direction, TransferCommand};
+use serde::Serialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tauri::{AppHandle, Listener, Manager};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+
+/// The page browsers get, to upload files.
+const PAGE: &str = include_str!("web.html");
+
+/// The most a request head (the request line and headers) can take.
+const MAX_HEAD: usize = 8 * 1024;
+
+/// How long a browser has to send its request head.
+const HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a browser can go without sending (or taking) any of a file before the transfer is
+/// aborted.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The size of each chunk read from (or written to) a browser.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The headers sent with every response: one request per connection, nothing cached or sniffed.
+const COMMON_HEADERS: &str =
+    "Connection: close\r\nCache-Control: no-store\r\nX-Content-Type-Options: nosniff\r\n";
+
+/// A file the user shares with browsers.
+#[derive(Serialize, Clone)]
+pub struct SharedFile {
+    /// The secret part of the file's link, so only those the user gives it to can download it.
+    pub id: String,
+    pub name: String,
+    pub size: u64,
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+/// An upload the user accepted, waiting for the browser to send its content.
+struct Upload {
+    name: String,
+    size: u64,
+    /// Whether the user was told the file replaces an existing one.
+    replaces: bool,
+    /// Only the browser that asked can send it.
+    ip: IpAddr,
+    accepted_at: Instant,
+}
+
+/// What the web page serves: the files the user shares, and the uploads it accepted.
+#[derive(Default)]
+pub struct Web {
+    shares: Mutex<Vec<SharedFile>>,
+    uploads: Mutex<HashMap<String, Upload>>,
+}
+
+impl Web {
+    /// Shares a file with browsers, returning it (or the existing share of the same file).
+    pub fn share(&self, path: &Path) -> Result<SharedFile, String> {
+        let metadata = std::fs::metadata(path).map_err(|e| e.to_string())?;
+        if !metadata.is_file() {
+            return Err("Only files can be shared.".to_string());
+        }
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or("Invalid file path.")?
+            .to_string();
+
+        let mut shares = self.shares.lock().unwrap();
+        if let Some(shared) = shares.iter().find(|s| s.path == path) {
+            return Ok(shared.clone());
+        }
+
+        let shared = SharedFile {
+            id: random_hex(16),
+            name,
+            size: metadata.len(),
+            path: path.to_path_buf(),
+        };
+        shares.push(shared.clone());
+        Ok(shared)
+    }
+
+    /// Stops sharing a file, returning whether it was shared.
+    pub fn unshare(&self, id: &str) -> bool {
+        let mut shares = self.shares.lock().unwrap();
+        let len = shares.len();
+        shares.retain(|s| s.id != id);
+        shares.len() != len
+    }
+
+    pub fn shared(&self) -> Vec<SharedFile> {
+        self.shares.lock().unwrap().clone()
+    }
+
+    fn find(&self, id: &str) -> Option<SharedFile> {
+        self.shares
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|s| s.id == id)
+            .cloned()
+    }
+
+    /// Takes the accepted upload with the given token, if it was accepted for this address.
+    fn take_upload(&self, token: &str, ip: IpAddr) -> Option<Upload> {
+        let mut uploads = self.uploads.lock().unwrap();
+        uploads.retain(|_, upload| upload.accepted_at.elapsed() < ASK_FILE_TIMEOUT);
+        uploads.remove(token).filter(|upload| upload.ip == ip)
+    }
+}
+
+/// Returns the link to download a shared file from, for the user to give to whoever it is for.
+pub fn share_link(app: &AppHandle, id: &str) -> Result<String, String> {
+    if !app.state::<Config>().get().web_page {
+        return Err("The web page is turned off.".to_string());
+    }
+    if app.state::<Web>().find(id).is_none() {
+        return Err("This file is not shared.".to_string());
+    }
+
+    Ok(format!("http://{}/files/{}", card::address(app)?, id))
+}
+
+/// A browser's request, with whatever of its body came along with the head.
+struct Request {
+    method: String,
+    path: String,
+    query: HashMap<String, String>,
+    content_length: u64,
+    body_start: Vec<u8>,
+}
+
+/// Looks at the start of a connection (without consuming it), returning whether it is a
+/// WebSocket upgrade, so from a peer rather than a browser.
+pub async fn is_websocket(stream: &TcpStream) -> bool {
+    let mut buffer = vec![0; MAX_HEAD];
+    let peeked = tokio::time::timeout(HEAD_TIMEOUT, async {
+        loop {
+            let read = stream.peek(&mut buffer).await.unwrap_or(0);
+            if read == 0 || read == buffer.len() || head_end(&buffer[..read]).is_some() {
+                return read;
+            }
+
+            // Peeking does not wait for more data, so give the rest of the head time to arrive.
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap_or(0);
+
+    String::from_utf8_lossy(&buffer[..peeked])
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .any(|(name, value)| {
+            name.trim().eq_ignore_ascii_case("upgrade")
+                && value.trim().eq_ignore_ascii_case("websocket")
+        })
+}
+
+/// Answers a browser's request, one per connection.
+pub async fn serve(peer: SocketAddr, mut stream: TcpStream, app: &AppHandle) {
+    if let Err(e) = handle_request(peer, &mut stream, app).await {
+        println!("Error serving {}: {}", peer, e);
+    }
+    let _ = stream.shutdown().await;
+}
+
+async fn handle_request(
+    peer: SocketAddr,
+    stream: &mut TcpStream,
+    app: &AppHandle,
+) -> Result<(), String> {
+    let request = match tokio::time::timeout(HEAD_TIMEOUT, read_request(stream)).await {
+        Ok(Ok(request)) => request,
+        Ok(Err(e)) => return respond_error(stream, "400 Bad Request", &e).await,
+        Err(_) => return Ok(()),
+    };
+
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/") => {
+            respond(
+                stream,
+                "200 OK",
+                "text/html; charset=utf-8",
+                PAGE.as_bytes(),
+            )
+            .await
+        }
+        ("GET", path) if path.starts_with("/files/") => {
+            download(peer, stream, app, &path["/files/".len()..]).await
+        }
+        ("POST", "/ask") => ask(peer, stream, app, &request).await,
+        ("POST", path) if path.starts_with("/upload/") => {
+            let token = path["/upload/".len()..].to_string();
+            upload(peer, stream, app, &token, request).await
+        }
+        _ => respond_error(stream, "404 Not Found", "Not found.").await,
+    }
+}
+
+/// Sends a shared file to the browser.
+async fn download(
+    peer: SocketAddr,
+    stream: &mut TcpStream,
+    app: &AppHandle,
+    id: &str,
+) -> Result<(), String> {
+    let Some(shared) = app.state::<Web>().find(id) else {
+        return respond_error(stream, "404 Not Found", "This file is no longer shared.").await;
+    };
+    let mut file = match File::open(&shared.path).await {
+        Ok(file) => file,
+        Err(e) => return respond_error(stream, "404 Not Found", &e.to_string()).await,
+    };
+    let size = file.metadata().await.map_err(|e| e.to_string())?.len();
+
+    let head = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\n\
+         Content-Disposition: attachment; filename*=UTF-8''{}\r\n{}\r\n",
+        size,
+        percent_encode(&shared.name),
+        COMMON_HEADERS
+    );
+    stream
+        .write_all(head.as_bytes())
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let (mut active, mut commands) =
+        transfers::start(app, Direction::Send, &shared.name, size, &peer.to_string());
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut sent = 0;
+    while sent < size {
+        if !keep_going(app, &mut active, &mut commands).await {
+            return Ok(());
+        }
+
+        let read = file.read(&mut buffer).await.map_err(|e| e.to_string())?;
+        if read == 0 {
+            break;
+        }
+        tokio::time::timeout(IDLE_TIMEOUT, stream.write_all(&buffer[..read]))
+            .await
+            .map_err(|_| "The browser stopped receiving the file.".to_string())?
+            .map_err(|e| e.to_string())?;
+        sent += read as u64;
+        // The limits are checked again while waiting, so changing them applies right away.
+        let mut wait = limiter::throttle(app, &active.id, read as u64);
+        while !wait.is_zero() {
+            tokio::time::sleep(wait.min(limiter::RECHECK_INTERVAL)).await;
+            wait = limiter::throttle(app, &active.id, 0);
+        }
+
+        emit_p2p(
+            app,
+            "send_progress",
+            json!({
+                "transfer_id": active.id,
+                "file_name": shared.name,
+                "file_size": size,
+                "sent": sent,
+                "peer": peer
+            }),
+        );
+    }
+
+    if sent == size {
+        active.finish(Outcome::Completed, None, None);
+        emit_p2p(
+            app,
+            "send_complete",
+            json!({
+                "transfer_id": active.id,
+                "file_name": shared.name,
+                "file_size": size,
+                "peer": peer
+            }),
+        );
+    }
+    Ok(())
+}
+
+/// Asks the user whether to accept a file from the browser, answering with the token to upload it
+/// with once accepted.
+async fn ask(
+    peer: SocketAddr,
+    stream: &mut TcpStream,
+    app: &AppHandle,
+    request: &Request,
+) -> Result<(), String> {
+    let name = request.query.get("name").cloned().unwrap_or_default();
+    let size = request.query.get("size").and_then(|size| size.parse().ok());
+    let (Some(name), Some(size)) = (receive::sanitize_name(&name), size) else {
+        return respond_error(stream, "400 Bad Request", "Invalid file.").await;
+    };
+
+    // Files going against the receive policy are refused without asking.
+    let dir = receive_dir(app).map_err(|e| e.to_string())?;
+    let policy = app.state::<Config>().get();
+    let checked = match receive::target_name(&dir, &name, policy.name_conflict, |_| false) {
+        Some(target) => receive::check_offer(&policy, &dir, size, size).map(|()| target),
+        None => Err("No name to save the file as.".to_string()),
+    };
+    let (name, exists) = match checked {
+        Ok(target) => target,
+        Err(reason) => {
+            emit_p2p(
+                app,
+                "file_refused",
+                json!({"file_name": name, "file_size": size, "error": reason, "peer": peer}),
+            );
+            return respond_error(stream, "403 Forbidden", &reason).await;
+        }
+    };
+
+    // Wait for the user's answer, like for a file offered by a peer.
+    let (sender, mut receiver) = mpsc::unbounded_channel::<bool>();
+    let listener = listen_answer(app, "e_p2p_ask_file", peer, sender);
+    emit_p2p(
+        app,
+        "ask_file",
+        json!({
+            "file_name": name,
+            "file_size": size,
+            "exists": exists,
+            "browser": true,
+            "peer": peer
+        }),
+    );
+    let answer = tokio::time::timeout(ASK_FILE_TIMEOUT, receiver.recv()).await;
+    app.unlisten(listener);
+
+    match answer {
+        Ok(Some(true)) => {
+            let token = random_hex(16);
+            app.state::<Web>().uploads.lock().unwrap().insert(
+                token.clone(),
+                Upload {
+                    name,
+                    size,
+                    replaces: exists,
+                    ip: peer.ip(),
+                    accepted_at: Instant::now(),
+                },
+            );
+            let body = json!({ "token": token }).to_string();
+            respond(stream, "200 OK", "application/json", body.as_bytes()).await
+        }
+        Ok(_) => respond_error(stream, "403 Forbidden", "The file was declined.").await,
+        Err(_) => {
+            emit_p2p(
+                app,
+                "ask_file_expired",
+                json!({"file_name": name, "peer": peer}),
+            );
+            respond_error(stream, "403 Forbidden", "Nobody answered.").await
+        }
+    }
+}
+
+/// Receives an accepted file from the browser into the download folder.
+async fn upload(
+    peer: SocketAddr,
+    stream: &mut TcpStream,
+    app: &AppHandle,
+    token: &str,
+    request: Request,
+) -> Result<(), String> {
+    let Some(upload) = app.state::<Web>().take_upload(token, peer.ip()) else {
+        return respond_error(stream, "403 Forbidden", "The file was not accepted.").await;
+    };
+    if request.content_length != upload.size {
+        return respond_error(stream, "400 Bad Request", "The size does not match.").await;
+    }
+
+    // The data goes to a partial file, only moved to its final name once complete.
+    let dir = receive_dir(app).map_err(|e| e.to_string())?;
+    let partial_path = dir.join(format!("{}.{}.part", upload.name, token));
+    let mut file = File::create(&partial_path)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let (mut active, mut commands) = transfers::start(
+        app,
+        Direction::Receive,
+        &upload.name,
+        upload.size,
+        &peer.to_string(),
+    );
+    let mut data = request.body_start;
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut received = 0;
+    let mut cancelled = false;
+    let error = loop {
+        data.truncate((upload.size - received) as usize);
+        if let Err(e) = file.write_all(&data).await {
+            break Some(e.to_string());
+        }
+        received += data.len() as u64;
+        emit_p2p(
+            app,
+            "file_progress",
+            json!({
+                "transfer_id": active.id,
+                "file_name": upload.name,
+                "file_size": upload.size,
+                "received": received,
+                "peer": peer
+            }),
+        );
+
+        if received == upload.size {
+            break None;
+        }
+        if !keep_going(app, &mut active, &mut commands).await {
+            cancelled = true;
+            break Some("The transfer was cancelled.".to_string());
+        }
+        match tokio::time::timeout(IDLE_TIMEOUT, stream.read(&mut buffer)).await {
+            Ok(Ok(0)) => break Some("The transfer was interrupted.".to_string()),
+            Ok(Ok(read)) => data = buffer[..read].to_vec(),
+            Ok(Err(e)) => break Some(e.to_string()),
+            Err(_) => break Some("The browser stopped sending the file.".to_string()),
+        }
+    };
+
+    let saved = match error {
+        None => match file.flush().await {
+            Ok(()) => save(app, &dir, &upload, &partial_path).await,
+            Err(e) => Err(e.to_string()),
+        },
+        Some(error) => Err(error),
+    };
+    drop(file);
+
+    match saved {
+        Ok(path) => {
+            active.finish(Outcome::Completed, None, None);
+            emit_p2p(
+                app,
+                "file_received",
+                json!({
+                    "file_name": upload.name,
+                    "file_size": upload.size,
+                    "path": path,
+                    "peer": peer
+                }),
+            );
+            respond(stream, "200 OK", "application/json", b"{}").await
+        }
+        Err(error) => {
+            // Browsers can't resume, so there is no point keeping what was received.
+            let _ = tokio::fs::remove_file(&partial_path).await;
+            if !cancelled {
+                active.finish(Outcome::Failed, None, Some(error.clone()));
+            }
+            emit_p2p(
+                app,
+                "file_failed",
+                json!({
+                    "file_name": upload.name,
+                    "file_size": upload.size,
+                    "error": error,
+                    "resumable": false,
+                    "peer": peer
+                }),
+            );
+            respond_error(stream, "500 Internal Server Error", &error).await
+        }
+    }
+}
+
+/// Moves a received upload to its final name, returning where it was saved.
+///
+/// Another file may have taken the name since the user accepted the upload, so the name is picked
+/// again, only replacing that file if the policy says so (and the user was told, when asked).
+async fn save(
+    app: &AppHandle,
+    dir: &Path,
+    upload: &Upload,
+    partial_path: &Path,
+) -> Result<PathBuf, String> {
+    let policy = match app.state::<Config>().get().name_conflict {
+        ConflictPolicy::Ask if !upload.replaces => ConflictPolicy::Rename,
+        policy => policy,
+    };
+    let (name, _) = receive::target_name(dir, &upload.name, policy, |_| false)
+        .ok_or("No name to save the file as.")?;
+    let path = dir.join(name);
+    tokio::fs::rename(partial_path, &path)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    Ok(path)
+}
+
+/// Handles the commands for a transfer with a browser, waiting while it is paused. Returns
+/// whether the transfer should go on (it was not cancelled).
+async fn keep_going(
+    app: &AppHandle,
+    active: &mut ActiveTransfer,
+    commands: &mut mpsc::UnboundedReceiver<TransferCommand>,
+) -> bool {
+    let mut paused = false;
+    loop {
+        let command = if paused {
+            commands.recv().await
+        } else {
+            match commands.try_recv() {
+                Ok(command) => Some(command),
+                Err(_) => return true,
+            }
+        };
+
+        match command {
+            Some(TransferCommand::Cancel) | None => {
+                active.finish(Outcome::Cancelled, None, None);
+                return false;
+            }
+            Some(command) => {
+                paused = command == TransferCommand::Pause;
+                transfers::report_paused(app, &active.id, paused, false);
+            }
+        }
+    }
+}
+
+/// Reads the request head, keeping what was read of the body past it.
+async fn read_request(stream: &mut TcpStream) -> Result<Request, String> {
+    let mut head = Vec::new();
+    let mut buffer = vec![0; MAX_HEAD];
+    let end = loop {
+        let read = stream.read(&mut buffer).await.map_err(|e| e.to_string())?;
+        if read == 0 {
+            return Err("The request ended early.".to_string());
+        }
+        head.extend_from_slice(&buffer[..read]);
+
+        if let Some(end) = head_end(&head) {
+            break end;
+        }
+        if head.len() >= MAX_HEAD {
+            return Err("The request head is too long.".to_string());
+        }
+    };
+    let body_start = head.split_off(end);
+
+    let head = String::from_utf8(head).map_err(|_| "Invalid request.".to_string())?;
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let (Some(method), Some(target)) = (request_line.next(), request_line.next()) else {
+        return Err("Invalid request.".to_string());
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+    let content_length = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+        .map(|(_, value)| value.trim().parse())
+        .transpose()
+        .map_err(|_| "Invalid content length.".to_string())?
+        .unwrap_or(0);
+
+    Ok(Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        query: query
+            .split('&')
+            .filter_map(|pair| {
+                let (key, value) = pair.split_once('=')?;
+                Some((percent_decode(key)?, percent_decode(value)?))
+            })
+            .collect(),
+        content_length,
+        body_start,
+    })
+}
+
+/// Returns where the body starts, once the whole head is in.
+fn head_end(data: &[u8]) -> Option<usize> {
+    data.windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map(|position| position + 4)
+}
+
+async fn respond(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> Result<(), String> {
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n{}\r\n",
+        status,
+        content_type,
+        body.len(),
+        COMMON_HEADERS
+    );
+    stream
+        .write_all(head.as_bytes())
+        .await
+        .map_err(|e| e.to_string())?;
+    stream.write_all(body).await.map_err(|e| e.to_string())
+}
+
+async fn respond_error(stream: &mut TcpStream, status: &str, error: &str) -> Result<(), String> {
+    let body = json!({ "error": error }).to_string();
+    respond(stream, status, "application/json", body.as_bytes()).await
+}
+
+/// Decodes a URL query component (`+` being a space), or `None` if it is not valid UTF-8.
+fn percent_decode(value: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut chars = value.bytes();
+    while let Some(byte) = chars.next() {
+        match byte {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                // Checked first, as parsing alone would take a sign (`%+1`).
+                let hex = [chars.next()?, chars.next()?];
+                if !hex.iter().all(u8::is_ascii_hexdigit) {
+                    return None;
+                }
+                bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            }
+            byte => bytes.push(byte),
+        }
+    }
+
+    String::from_utf8(bytes).ok()
+}
+
+/// Encodes a file name for the `filename*` parameter of `Content-Disposition`.
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            byte => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percent_decode_decodes_escapes_and_spaces() {
+        assert_eq!(percent_decode("a%20b+c").as_deref(), Some("a b c"));
+        assert_eq!(percent_decode("%C3%A9t%c3%a9").as_deref(), Some("été"));
+        assert_eq!(percent_decode("100%25").as_deref(), Some("100%"));
+        assert_eq!(percent_decode("%2B").as_deref(), Some("+"));
+        assert_eq!(percent_decode("").as_deref(), Some(""));
+    }
+
+    #[test]
+    fn percent_decode_refuses_invalid_escapes() {
+        for value in ["%", "%2", "a%zz", "%+1", "%-1", "%é"] {
+            assert_eq!(percent_decode(value), None, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn percent_decode_refuses_invalid_utf8() {
+        assert_eq!(percent_decode("%C3"), None);
+        assert_eq!(percent_decode("%FF%FE"), None);
+    }
+
+    #[test]
+    fn percent_encode_round_trips() {
+        let name = "rapport final (v2) été+100%.pdf";
+        assert_eq!(percent_decode(&percent_encode(name)).as_deref(), Some(name));
+    }
+}
@@ -0,0 +1,69 @@
+import { emit, listen, type UnlistenFn } from '@tauri-apps/api/event';
+import { writable } from 'svelte/store';
+import { addAlert } from './alert';
+
+// A question about a peer waiting for the user's answer.
+export interface P2pPrompt {
+    id: string;
+    event: string;
+    data: any;
+}
+
+// The events the user answers, and the event each answer is sent back on.
+const answerEvents: Record<string, string> = {
+    pair_request: 'e_p2p_pair',
+    pair_code: 'e_p2p_pair_code',
+    // Folders are answered like files.
+    ask_file: 'e_p2p_ask_file',
+    ask_folder: 'e_p2p_ask_file',
+};
+
+export const p2pPrompts = writable<P2pPrompt[]>([]);
+
+function removePrompts(peer: string, events: string[]) {
+    p2pPrompts.update((all) =>
+        all.filter((p) => p.data.peer !== peer || !events.includes(p.event))
+    );
+}
+
+// Listens to the P2P events of the backend, returning the function to stop.
+export async function listenP2p(): Promise<UnlistenFn> {
+    return listen<{ event: string; data: any }>('e_p2p', ({ payload }) => {
+        const { event, data } = payload;
+
+        if (event in answerEvents) {
+            const id = `${Date.now()}${Math.floor(Math.random() * 10000)}`;
+            p2pPrompts.update((all) => [...all, { id, event, data }]);
+            return;
+        }
+
+        switch (event) {
+            case 'paired':
+                removePrompts(data.peer, ['pair_request', 'pair_code']);
+                addAlert({
+                    message: `Paired with ${data.device_name}!`,
+                    type: 'success',
+                    timeout: 5000,
+                });
+                break;
+            case 'pair_declined':
+                removePrompts(data.peer, ['pair_request']);
+                break;
+            // Nobody answered in time, so the offer was declined.
+            case 'ask_file_expired':
+                removePrompts(data.peer, ['ask_file', 'ask_folder']);
+                addAlert({
+                    message: `Declined ${data.file_name}, nobody answered in time.`,
+                    type: 'info',
+                    timeout: 5000,
+                });
+                break;
+        }
+    });
+}
+
+// Sends the user's answer back to the backend.
+export async function answerPrompt(prompt: P2pPrompt, accept: boolean) {
+    p2pPrompts.update((all) => all.filter((p) => p.id !== prompt.id));
+    await emit(answerEvents[prompt.event], { peer: prompt.data.peer, accept });
+}
@@ -1,5 +1,6 @@
 import { getUserTimeZone } from './date';
 import { configStore } from '../stores/config';
+import { addAlert } from '../stores/alert';
 import {
     BaseDirectory,
     exists,
@@ -8,6 +9,7 @@ import {
     writeTextFile,
 } from '@tauri-apps/plugin-fs';
 import { extendObject } from './object';
+import { invoke } from '@tauri-apps/api/core';
 import isEqual from 'lodash.isequal';
 
 export type ConfigUnixFetchFormat =
@@ -19,11 +21,46 @@ export type ConfigUnixFetchFormat =
 
 export interface Config {
     locale: string;
-    shortcuts: { unixToReadable: string };
+    // An empty shortcut is not registered.
+    shortcuts: { unixToReadable: string; sendSelection: string };
     unix: {
         fetchFormat: ConfigUnixFetchFormat;
         timeZone: string;
     };
+    p2p: {
+        // KiB per second, 0 for no limit.
+        globalRateLimit: number;
+        transferRateLimit: number;
+        // Applied when the app starts.
+        listenAddress: string;
+        listenPort: number;
+        listenInterface: string | null;
+        randomPortFallback: boolean;
+        discovery: boolean;
+        // Lets browsers upload files, and download the shared ones by their link.
+        webPage: boolean;
+        // Declines pair requests not made with our connection card.
+        cardPairingOnly: boolean;
+        // Seconds, an idle timeout of 0 keeps connections open.
+        pingInterval: number;
+        maxMissedPongs: number;
+        idleTimeout: number;
+        // CIDR networks (or addresses), an empty allow list allows any.
+        allowedNetworks: string[];
+        deniedNetworks: string[];
+        // 0 for no limit.
+        maxConnectionsPerMinute: number;
+        maxConnections: number;
+        // The downloads folder when null.
+        receiveDir: string | null;
+        nameConflict: 'rename' | 'overwrite' | 'ask';
+        // MiB, 0 for no limit.
+        maxReceiveSize: number;
+        // What happens to a cancelled transfer's partially received file.
+        partialPolicy: 'delete' | 'keep';
+        // The paired device the selection is sent to, picked each time when null.
+        defaultPeer: string | null;
+    };
 }
 
 export function validateAndUpdateConfig(config: Config): Config {
@@ -35,11 +72,35 @@ export function defaultConfig(): Config {
         locale: 'en',
         shortcuts: {
             unixToReadable: 'CmdOrCtrl+Q',
+            sendSelection: '',
         },
         unix: {
             fetchFormat: 'auto',
             timeZone: getUserTimeZone(),
         },
+        p2p: {
+            globalRateLimit: 0,
+            transferRateLimit: 0,
+            listenAddress: '0.0.0.0',
+            listenPort: 15446,
+            listenInterface: null,
+            randomPortFallback: true,
+            discovery: true,
+            webPage: false,
+            cardPairingOnly: false,
+            pingInterval: 15,
+            maxMissedPongs: 3,
+            idleTimeout: 300,
+            allowedNetworks: [],
+            deniedNetworks: [],
+            maxConnectionsPerMinute: 30,
+            maxConnections: 32,
+            receiveDir: null,
+            nameConflict: 'rename',
+            maxReceiveSize: 0,
+            partialPolicy: 'delete',
+            defaultPeer: null,
+        },
     };
 }
 
@@ -54,6 +115,15 @@ export async function writeConfig(config: Config) {
     await writeTextFile('config.json', JSON.stringify(config), {
         baseDir: BaseDirectory.AppConfig,
     });
+
+    // Let the backend pick up the new P2P settings (applies to running transfers too), the ones
+    // it can't read keep their defaults.
+    const { errors } = await invoke<{ errors: string[] }>(
+        'c_p2p_reload_config'
+    );
+    for (const error of errors) {
+        addAlert({ message: error, type: 'error', timeout: 10000 });
+    }
 }
 
 export async function getConfig() {
@@ -0,0 +1,17 @@
+// Formats a size in bytes for display (like "1.5 MiB").
+export function formatSize(bytes: number): string {
+    const units = ['B', 'KiB', 'MiB', 'GiB', 'TiB'];
+    let size = bytes;
+    let unit = 0;
+    while (size >= 1024 && unit < units.length - 1) {
+        size /= 1024;
+        unit++;
+    }
+
+    return unit === 0 ? `${size} B` : `${size.toFixed(1)} ${units[unit]}`;
+}
+
+// Formats how many times smaller compressed data was on the wire (like "2.35x").
+export function formatRatio(ratio: number): string {
+    return `${ratio.toFixed(2)}x`;
+}